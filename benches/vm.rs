@@ -0,0 +1,72 @@
+//! Benchmarks the VM on the compiled Brainfuck programs and on `leaf.leaf`
+//! interpreting the tutorial programs, each as is and optimized, with each
+//! engine.
+//!
+//! Run with `cargo bench`, optionally with a filter on the benchmark names.
+//! The JIT is included with `--features jit`.
+//! Each benchmark runs for about a second and reports the median time per run.
+
+use std::fs;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use leafy::{Engine, Program, Semantics, VM};
+
+fn main() {
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let leaf_leaf = fs::read_to_string("programs/leaf.leaf").unwrap();
+    let mut benches = vec![("leaf.leaf".to_owned(), Program::parse(&leaf_leaf).unwrap())];
+    for name in ["42_nowrap", "42_wrap", "beaver_17"] {
+        let src = fs::read_to_string(format!("programs/brainfuck/{name}.leaf")).unwrap();
+        benches.push((format!("bf {name}"), Program::parse(&src).unwrap()));
+    }
+    // leaf.leaf does not implement `?`, so programs using it do not terminate.
+    for name in ["2_movement", "4_loops", "7_numerals", "9_lists"] {
+        let src = fs::read_to_string(format!("programs/tutorial/{name}.leaf")).unwrap();
+        let prog = Program::parse(&src).unwrap().wrap_meta().unwrap();
+        benches.push((format!("meta {name}"), prog));
+    }
+    let vms = benches.into_iter().flat_map(|(name, prog)| {
+        let opt = VM::optimized(prog.clone(), Semantics::LEAFY);
+        [
+            (name.clone(), VM::new(prog)),
+            (format!("{name} (opt)"), opt),
+        ]
+    });
+    for (name, vm) in vms {
+        for (engine, engine_name) in [
+            (Engine::Interpreter, "interpreter"),
+            (Engine::Threaded, "threaded"),
+            #[cfg(feature = "jit")]
+            (Engine::Jit, "jit"),
+        ] {
+            let name = format!("{name} {engine_name}");
+            if filter.as_ref().is_some_and(|filter| !name.contains(filter)) {
+                continue;
+            }
+            // The VM is built and compiled once, so only running it is
+            // measured.
+            let mut vm = vm.clone();
+            vm.set_engine(engine).unwrap();
+            let mut times = bench(|| {
+                let mut vm = vm.clone();
+                vm.run().unwrap();
+                black_box(vm);
+            });
+            times.sort_unstable();
+            let median = times[times.len() / 2];
+            println!("{name:<34} {median:>12.3?}/iter ({} iters)", times.len());
+        }
+    }
+}
+
+fn bench(mut f: impl FnMut()) -> Vec<Duration> {
+    let start = Instant::now();
+    let mut times = Vec::new();
+    while times.is_empty() || start.elapsed() < Duration::from_secs(1) {
+        let iter = Instant::now();
+        f();
+        times.push(iter.elapsed());
+    }
+    times
+}
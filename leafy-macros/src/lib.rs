@@ -0,0 +1,192 @@
+//! Macros for embedding Leaf programs in Rust, which are checked for
+//! unmatched loop brackets at compile time.
+
+use leafy::{Inst, Program};
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Attribute, Ident, LitStr, Token, Visibility};
+
+/// Compiles a Leaf program, given as a string literal.
+///
+/// Alone, it expands to a constant expression of type `leafy::Program`, which
+/// borrows static instructions:
+///
+/// ```text
+/// const PROG: Program = leaf!("+<*>(^)");
+/// ```
+///
+/// With a function signature, it expands to a function that runs the program
+/// with Leafy semantics on a `leafy::tree::RootedTree`, as generated by
+/// `Program::to_rust`:
+///
+/// ```text
+/// leaf!(pub fn lists = "{+<*>}(^)");
+///
+/// let mut tree = RootedTree::new();
+/// lists(&mut tree)?;
+/// ```
+#[proc_macro]
+pub fn leaf(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as LeafInput);
+    let src = input.src.value();
+    let (prog, _, errors) = Program::parse_recovering(&src);
+    if let Some(err) = errors
+        .iter()
+        .map(|err| {
+            let span = err
+                .pos()
+                .map_or(input.src.span(), |pos| char_span(&input.src, pos.offset));
+            syn::Error::new(span, err)
+        })
+        .reduce(|mut errs, err| {
+            errs.combine(err);
+            errs
+        })
+    {
+        return err.to_compile_error().into();
+    }
+    match input.func {
+        Some(Func { attrs, vis, name }) => {
+            let func = prog.to_rust(&name.to_string());
+            let func = func.parse::<TokenStream2>().unwrap();
+            quote!(#(#attrs)* #vis #func).into()
+        }
+        None => {
+            let insts = prog.insts().iter().map(inst);
+            quote!(const { ::leafy::Program::from_static(&[#(#insts),*]) }).into()
+        }
+    }
+}
+
+/// The input to `leaf!`, as `"…"` or `fn name = "…"`, with optional attributes
+/// and visibility on the function.
+struct LeafInput {
+    func: Option<Func>,
+    src: LitStr,
+}
+
+struct Func {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    name: Ident,
+}
+
+impl Parse for LeafInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(LitStr) {
+            let src = input.parse()?;
+            return Ok(LeafInput { func: None, src });
+        }
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        input.parse::<Token![fn]>()?;
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let src = input.parse()?;
+        if input.peek(Token![;]) {
+            input.parse::<Token![;]>()?;
+        }
+        Ok(LeafInput {
+            func: Some(Func { attrs, vis, name }),
+            src,
+        })
+    }
+}
+
+fn inst(inst: &Inst) -> TokenStream2 {
+    match *inst {
+        Inst::MoveLeft => quote!(::leafy::Inst::MoveLeft),
+        Inst::MoveRight => quote!(::leafy::Inst::MoveRight),
+        Inst::MoveUp => quote!(::leafy::Inst::MoveUp),
+        Inst::PushRoot => quote!(::leafy::Inst::PushRoot),
+        Inst::PopRoot => quote!(::leafy::Inst::PopRoot),
+        Inst::LoopHead(tail) => {
+            let tail = Literal::usize_unsuffixed(tail);
+            quote!(::leafy::Inst::LoopHead(#tail))
+        }
+        Inst::LoopTail => quote!(::leafy::Inst::LoopTail),
+        Inst::NewLeft => quote!(::leafy::Inst::NewLeft),
+        Inst::NewRight => quote!(::leafy::Inst::NewRight),
+        Inst::Delete => quote!(::leafy::Inst::Delete),
+        Inst::Break => quote!(::leafy::Inst::Break),
+    }
+}
+
+/// Returns the span of the character at a byte offset in the value of a string
+/// literal, when the compiler supports subspans, or else of the whole literal.
+fn char_span(lit: &LitStr, offset: usize) -> Span {
+    let token = lit.token();
+    raw_offset(&token.to_string(), offset)
+        .and_then(|start| token.subspan(start..start + 1))
+        .unwrap_or_else(|| lit.span())
+}
+
+/// Converts a byte offset in the value of a string literal to one in its
+/// source, by skipping its quotes and escapes.
+fn raw_offset(raw: &str, offset: usize) -> Option<usize> {
+    if let Some(rest) = raw.strip_prefix('r') {
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        return Some(1 + hashes + 1 + offset);
+    }
+    let mut chars = raw.char_indices().skip(1).peekable();
+    let mut value_offset = 0;
+    while let Some((i, ch)) = chars.next() {
+        if ch == '\\' && chars.next_if(|&(_, ch)| ch == '\n').is_some() {
+            // A line continuation skips the following whitespace.
+            while chars.next_if(|&(_, ch)| ch.is_whitespace()).is_some() {}
+            continue;
+        }
+        if value_offset == offset {
+            return Some(i);
+        }
+        if ch != '\\' {
+            value_offset += ch.len_utf8();
+            continue;
+        }
+        match chars.next()?.1 {
+            'x' => {
+                chars.nth(1)?;
+                value_offset += 1;
+            }
+            'u' => {
+                let mut hex = String::new();
+                for (_, ch) in chars.by_ref().skip(1) {
+                    if ch == '}' {
+                        break;
+                    }
+                    hex.push(ch);
+                }
+                let ch = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)?;
+                value_offset += ch.len_utf8();
+            }
+            _ => value_offset += 1,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_offsets() {
+        let cases: &[(&str, &str)] = &[
+            (r#""+(<""#, "+(<"),
+            (r##"r#"é(\n"#"##, "é(\\n"),
+            (r#""\t\"é\x41\u{1F33F}(""#, "\t\"é\x41\u{1F33F}("),
+            ("\"+\\\n    (\"", "+("),
+        ];
+        for &(raw, value) in cases {
+            let lit = syn::parse_str::<LitStr>(raw).unwrap();
+            assert_eq!(lit.value(), value, "{raw}");
+            let offset = value.find('(').unwrap();
+            let start = raw_offset(raw, offset).unwrap();
+            assert_eq!(&raw[start..start + 1], "(", "{raw}");
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Tests of the `leaf!` macro, against the VM.
+
+use leafy::tree::RootedTree;
+use leafy::{Program, VM};
+use leafy_macros::leaf;
+
+leaf!(fn movement = "+*>+*<<^^^");
+leaf!(fn loops = "*>*>*>*>(^)(+>)");
+leaf!(fn rebasing = "*>*>{*>*>(^)(+>)}");
+leaf!(fn conditional = "*{(?+*)+}");
+leaf!(
+    /// Adds the numerals 3 and 2.
+    pub(crate) fn addition = "
+        *>*>*>*
+        (^)+<
+        *>*>*
+        (^)
+
+        (<{(>)?}-(^)(>)*(^))}
+    "
+);
+leaf!(fn demo = "*>*>*>*(^)+<+<+<+(^){(<)(?-(^)(>)*(^)(<))}");
+
+/// Checks that a compiled function builds the same tree as the VM.
+fn check(src: &str, func: fn(&mut RootedTree) -> Result<(), leafy::tree::OutOfMemory>) {
+    let mut tree = RootedTree::new();
+    func(&mut tree).unwrap();
+    let mut vm = VM::new(Program::parse(src).unwrap());
+    vm.run().unwrap();
+    assert_eq!(tree.diff(vm.tree()), None, "{src}");
+}
+
+#[test]
+fn functions() {
+    check("+*>+*<<^^^", movement);
+    check("*>*>*>*>(^)(+>)", loops);
+    check("*>*>{*>*>(^)(+>)}", rebasing);
+    check("*{(?+*)+}", conditional);
+    check("*>*>*>*(^)+<*>*>*(^)(<{(>)?}-(^)(>)*(^))}", addition);
+    check("*>*>*>*(^)+<+<+<+(^){(<)(?-(^)(>)*(^)(<))}", demo);
+}
+
+const NESTED: Program = leaf!("*{(?+*)+}(<(>)?-)");
+static EMPTY: Program = leaf!("# comments are ignored");
+
+#[test]
+fn expressions() {
+    let src = "*{(?+*)+}(<(>)?-)";
+    assert_eq!(NESTED, Program::parse(src).unwrap());
+    assert_eq!(EMPTY, Program::parse("").unwrap());
+    assert_eq!(leaf!(r"((?)) # ()"), Program::parse("((?))()").unwrap());
+}
+
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,5 @@
+use leafy_macros::leaf;
+
+fn main() {
+    let _ = leaf!("+(<");
+}
@@ -0,0 +1,5 @@
+use leafy_macros::leaf;
+
+leaf!(fn unmatched = ")+(");
+
+fn main() {}
@@ -0,0 +1,141 @@
+use crate::{Inst, Program};
+
+/// A program as a tree, with loop bodies nested in their loops.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Block(pub Vec<Stmt>);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Stmt {
+    /// `<`
+    MoveLeft,
+    /// `>`
+    MoveRight,
+    /// `^`
+    MoveUp,
+    /// `{`
+    PushRoot,
+    /// `}`
+    PopRoot,
+    /// `( … )`
+    Loop(Block),
+    /// `+`
+    NewLeft,
+    /// `*`
+    NewRight,
+    /// `-`
+    Delete,
+    /// `?`
+    Break,
+}
+
+impl Block {
+    pub fn new() -> Self {
+        Block(Vec::new())
+    }
+
+    pub fn stmts(&self) -> &[Stmt] {
+        &self.0
+    }
+
+    fn flatten_into(&self, prog: &mut Vec<Inst>) {
+        for stmt in &self.0 {
+            let inst = match stmt {
+                Stmt::MoveLeft => Inst::MoveLeft,
+                Stmt::MoveRight => Inst::MoveRight,
+                Stmt::MoveUp => Inst::MoveUp,
+                Stmt::PushRoot => Inst::PushRoot,
+                Stmt::PopRoot => Inst::PopRoot,
+                Stmt::Loop(body) => {
+                    let head = prog.len();
+                    prog.push(Inst::LoopHead(usize::MAX));
+                    body.flatten_into(prog);
+                    prog[head] = Inst::LoopHead(prog.len());
+                    Inst::LoopTail
+                }
+                Stmt::NewLeft => Inst::NewLeft,
+                Stmt::NewRight => Inst::NewRight,
+                Stmt::Delete => Inst::Delete,
+                Stmt::Break => Inst::Break,
+            };
+            prog.push(inst);
+        }
+    }
+}
+
+impl From<&Program> for Block {
+    fn from(prog: &Program) -> Self {
+        let mut blocks = vec![Block::new()];
+        for inst in prog.insts() {
+            let stmt = match inst {
+                Inst::MoveLeft => Stmt::MoveLeft,
+                Inst::MoveRight => Stmt::MoveRight,
+                Inst::MoveUp => Stmt::MoveUp,
+                Inst::PushRoot => Stmt::PushRoot,
+                Inst::PopRoot => Stmt::PopRoot,
+                Inst::LoopHead(_) => {
+                    blocks.push(Block::new());
+                    continue;
+                }
+                Inst::LoopTail => Stmt::Loop(blocks.pop().unwrap()),
+                Inst::NewLeft => Stmt::NewLeft,
+                Inst::NewRight => Stmt::NewRight,
+                Inst::Delete => Stmt::Delete,
+                Inst::Break => Stmt::Break,
+            };
+            blocks.last_mut().unwrap().0.push(stmt);
+        }
+        debug_assert!(blocks.len() == 1);
+        blocks.pop().unwrap()
+    }
+}
+
+impl From<Program> for Block {
+    fn from(prog: Program) -> Self {
+        Block::from(&prog)
+    }
+}
+
+impl From<&Block> for Program {
+    fn from(block: &Block) -> Self {
+        let mut prog = Vec::new();
+        block.flatten_into(&mut prog);
+        Program(prog.into())
+    }
+}
+
+impl From<Block> for Program {
+    fn from(block: Block) -> Self {
+        Program::from(&block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus;
+
+    #[test]
+    fn round_trip_corpus() {
+        for (name, prog) in corpus::programs() {
+            let block = Block::from(&prog);
+            assert_eq!(Program::from(&block), prog, "{name}");
+        }
+    }
+
+    #[test]
+    fn nesting() {
+        let prog = Program::parse("+(<?(>-)?)^").unwrap();
+        let block = Block(vec![
+            Stmt::NewLeft,
+            Stmt::Loop(Block(vec![
+                Stmt::MoveLeft,
+                Stmt::Break,
+                Stmt::Loop(Block(vec![Stmt::MoveRight, Stmt::Delete])),
+                Stmt::Break,
+            ])),
+            Stmt::MoveUp,
+        ]);
+        assert_eq!(Block::from(&prog), block);
+        assert_eq!(Program::from(block), prog);
+    }
+}
@@ -0,0 +1,353 @@
+use std::fmt;
+
+use crate::{Inst, Program};
+
+/// A program compiled for execution, where each loop tail and break carries
+/// its jump target, so that engines jump directly instead of consulting the
+/// loop stack. [`Code::new`] has an op for each instruction of the program, at
+/// the same index, while [`Code::optimized`] fuses common idioms into
+/// superinstructions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Code(Vec<Op>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Op {
+    /// `<`
+    MoveLeft,
+    /// `>`
+    MoveRight,
+    /// `^`
+    MoveUp,
+    /// `{`
+    PushRoot,
+    /// `}`
+    PopRoot,
+    /// `(`, with the index of its `)`
+    LoopHead(usize),
+    /// `)`, with the index of its `(`
+    LoopTail(usize),
+    /// `+`
+    NewLeft,
+    /// `*`
+    NewRight,
+    /// `-`
+    Delete,
+    /// `?`, with the index of the `)` of its enclosing loop, or the length of
+    /// the program when outside of a loop
+    Break(usize),
+    /// `(^)`
+    MoveUpToRoot,
+    /// `(<)`
+    MoveLeftToEnd,
+    /// `(>)`
+    MoveRightToEnd,
+    /// `^` repeated, at least twice
+    RepeatMoveUp(usize),
+    /// `+<` repeated, at least twice. It fails without changing the tree when
+    /// there are not enough nodes for the whole chain.
+    NewLeftChain(usize),
+    /// `*>` repeated, at least twice, and atomic like [`Op::NewLeftChain`]
+    NewRightChain(usize),
+    /// `+<-`
+    DeleteLeft,
+    /// `*>-`
+    DeleteRight,
+}
+
+impl Code {
+    pub(crate) fn new(prog: &Program) -> Self {
+        Code::resolve(prog.insts().iter().map(|&inst| Op::from(inst)).collect())
+    }
+
+    /// Compiles a program, rewriting common idioms into superinstructions,
+    /// which execute in one step, with the same effect on the tree and
+    /// success flag as the instructions they replace. Since each
+    /// superinstruction counts as one step, step limits and program counters
+    /// differ from those of [`Code::new`].
+    pub(crate) fn optimized(prog: &Program) -> Self {
+        use Op::*;
+        let ops = prog
+            .insts()
+            .iter()
+            .map(|&inst| Op::from(inst))
+            .collect::<Vec<_>>();
+        let mut opt = Vec::with_capacity(ops.len());
+        let mut i = 0;
+        while i < ops.len() {
+            let (op, len) = match ops[i..] {
+                [LoopHead(_), MoveUp, LoopTail(_), ..] => (MoveUpToRoot, 3),
+                [LoopHead(_), MoveLeft, LoopTail(_), ..] => (MoveLeftToEnd, 3),
+                [LoopHead(_), MoveRight, LoopTail(_), ..] => (MoveRightToEnd, 3),
+                [NewLeft, MoveLeft, Delete, ..] => (DeleteLeft, 3),
+                [NewRight, MoveRight, Delete, ..] => (DeleteRight, 3),
+                [MoveUp, MoveUp, ..] => {
+                    let n = run_len(&ops[i..], &[MoveUp]);
+                    (RepeatMoveUp(n), n)
+                }
+                [NewLeft, MoveLeft, NewLeft, MoveLeft, ..] => {
+                    let n = run_len(&ops[i..], &[NewLeft, MoveLeft]);
+                    (NewLeftChain(n), 2 * n)
+                }
+                [NewRight, MoveRight, NewRight, MoveRight, ..] => {
+                    let n = run_len(&ops[i..], &[NewRight, MoveRight]);
+                    (NewRightChain(n), 2 * n)
+                }
+                [op, ..] => (op, 1),
+                [] => unreachable!(),
+            };
+            opt.push(op);
+            i += len;
+        }
+        // Superinstructions contain no `(`, `)` or `?`, so jumps between the
+        // remaining ops are resolved as usual.
+        Code::resolve(opt)
+    }
+
+    /// Fills in the jump targets of loops and breaks. The ops must have
+    /// balanced loops.
+    fn resolve(mut ops: Vec<Op>) -> Self {
+        let mut heads = Vec::new();
+        for pc in 0..ops.len() {
+            match ops[pc] {
+                Op::LoopHead(_) => heads.push(pc),
+                Op::LoopTail(_) => {
+                    let head = heads.pop().unwrap();
+                    ops[head] = Op::LoopHead(pc);
+                    ops[pc] = Op::LoopTail(head);
+                }
+                _ => {}
+            }
+        }
+        let mut tails = Vec::new();
+        for pc in 0..ops.len() {
+            match ops[pc] {
+                Op::LoopHead(tail) => tails.push(tail),
+                Op::LoopTail(_) => {
+                    tails.pop();
+                }
+                Op::Break(_) => ops[pc] = Op::Break(tails.last().copied().unwrap_or(ops.len())),
+                _ => {}
+            }
+        }
+        Code(ops)
+    }
+
+    pub(crate) fn get(&self, pc: usize) -> Option<&Op> {
+        self.0.get(pc)
+    }
+
+    pub(crate) fn ops(&self) -> &[Op] {
+        &self.0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl From<Inst> for Op {
+    /// Converts an instruction, with its jump targets left to be resolved.
+    fn from(inst: Inst) -> Self {
+        match inst {
+            Inst::MoveLeft => Op::MoveLeft,
+            Inst::MoveRight => Op::MoveRight,
+            Inst::MoveUp => Op::MoveUp,
+            Inst::PushRoot => Op::PushRoot,
+            Inst::PopRoot => Op::PopRoot,
+            Inst::LoopHead(_) => Op::LoopHead(0),
+            Inst::LoopTail => Op::LoopTail(0),
+            Inst::NewLeft => Op::NewLeft,
+            Inst::NewRight => Op::NewRight,
+            Inst::Delete => Op::Delete,
+            Inst::Break => Op::Break(0),
+        }
+    }
+}
+
+/// Prints the source of the instructions that an op was compiled from.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (src, n) = match *self {
+            Op::MoveLeft => ("<", 1),
+            Op::MoveRight => (">", 1),
+            Op::MoveUp => ("^", 1),
+            Op::PushRoot => ("{", 1),
+            Op::PopRoot => ("}", 1),
+            Op::LoopHead(_) => ("(", 1),
+            Op::LoopTail(_) => (")", 1),
+            Op::NewLeft => ("+", 1),
+            Op::NewRight => ("*", 1),
+            Op::Delete => ("-", 1),
+            Op::Break(_) => ("?", 1),
+            Op::MoveUpToRoot => ("(^)", 1),
+            Op::MoveLeftToEnd => ("(<)", 1),
+            Op::MoveRightToEnd => ("(>)", 1),
+            Op::RepeatMoveUp(n) => ("^", n),
+            Op::NewLeftChain(n) => ("+<", n),
+            Op::NewRightChain(n) => ("*>", n),
+            Op::DeleteLeft => ("+<-", 1),
+            Op::DeleteRight => ("*>-", 1),
+        };
+        for _ in 0..n {
+            f.write_str(src)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the repetitions of `pattern` at the start of `ops`.
+fn run_len(ops: &[Op], pattern: &[Op]) -> usize {
+    ops.chunks_exact(pattern.len())
+        .take_while(|chunk| *chunk == pattern)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{corpus, RunStatus, Semantics, VMError, VM};
+
+    #[test]
+    fn resolve_targets() {
+        use Op::*;
+        let prog = Program::parse("?(<?(?)>)?").unwrap();
+        assert_eq!(
+            Code::new(&prog).0,
+            [
+                Break(10),
+                LoopHead(8),
+                MoveLeft,
+                Break(8),
+                LoopHead(6),
+                Break(6),
+                LoopTail(4),
+                MoveRight,
+                LoopTail(1),
+                Break(10),
+            ],
+        );
+    }
+
+    #[test]
+    fn resolve_corpus() {
+        use Op::*;
+        for (name, prog) in corpus::programs() {
+            let code = Code::new(&prog);
+            assert_eq!(code.len(), prog.len(), "{name}");
+            for (pc, &op) in code.0.iter().enumerate() {
+                match op {
+                    LoopHead(tail) => assert_eq!(code.get(tail), Some(&LoopTail(pc)), "{name}"),
+                    LoopTail(head) => {
+                        assert!(matches!(code.get(head), Some(LoopHead(_))), "{name}")
+                    }
+                    Break(tail) => assert!(
+                        tail == code.len()
+                            || matches!(code.get(tail), Some(LoopTail(head)) if *head < pc),
+                        "{name}"
+                    ),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Runs a VM until it finishes or fails, or returns `None` if it does not
+    /// stop within the step limit.
+    fn run(mut vm: VM) -> Option<(Option<VMError>, VM)> {
+        match vm.run_for(10_000_000) {
+            Ok(RunStatus::Finished) | Err(VMError::Terminated) => Some((None, vm)),
+            Ok(RunStatus::OutOfFuel) => None,
+            Err(err) => Some((Some(err), vm)),
+        }
+    }
+
+    fn check_same(name: &str, prog: &Program) {
+        for semantics in [Semantics::LEAFY, Semantics::REFERENCE] {
+            // Some examples loop forever under the reference semantics, and
+            // the optimized code takes fewer steps, so only finished runs are
+            // compared.
+            let Some((err_a, a)) = run(VM::with_semantics(prog.clone(), semantics)) else {
+                continue;
+            };
+            let (err_b, b) = run(VM::optimized(prog.clone(), semantics)).unwrap();
+            assert_eq!(err_a, err_b, "{name}");
+            assert_eq!(a.tree().diff(b.tree()), None, "{name}");
+            assert_eq!(a.success, b.success, "{name}");
+        }
+    }
+
+    #[test]
+    fn optimize_corpus() {
+        for (name, prog) in corpus::programs() {
+            check_same(&name, &prog);
+        }
+    }
+
+    #[test]
+    fn superinstructions() {
+        use Op::*;
+        let cases = [
+            ("+<+<(^)", MoveUpToRoot),
+            ("+<+<(^)(^)", MoveUpToRoot),
+            ("+<+<^(<)", MoveLeftToEnd),
+            ("*>*>^^(>)", MoveRightToEnd),
+            ("+<+<+<^^^", RepeatMoveUp(3)),
+            ("+<^^^^", RepeatMoveUp(4)),
+            // Moving up fails at the root, which clears the success flag.
+            ("^^^", RepeatMoveUp(3)),
+            ("(<)", MoveLeftToEnd),
+            ("+<+<+<", NewLeftChain(3)),
+            ("*>*>", NewRightChain(2)),
+            ("+<-", DeleteLeft),
+            ("+<+<^+<-", DeleteLeft),
+            ("*>-", DeleteRight),
+            ("*>{*>-}", DeleteRight),
+        ];
+        for (src, op) in cases {
+            let prog = Program::parse(src).unwrap();
+            assert!(Code::optimized(&prog).0.contains(&op), "{src}");
+            check_same(src, &prog);
+        }
+    }
+
+    #[test]
+    fn superinstruction_boundaries() {
+        use Op::*;
+        let prog = Program::parse("*>*>*+<+<+<^^(>)").unwrap();
+        assert_eq!(
+            Code::optimized(&prog).0,
+            [
+                NewRightChain(2),
+                NewRight,
+                NewLeftChain(3),
+                RepeatMoveUp(2),
+                MoveRightToEnd,
+            ],
+        );
+        // Jumps around superinstructions index the optimized code.
+        let prog = Program::parse("+<(+<-^(^)?)?").unwrap();
+        assert_eq!(
+            Code::optimized(&prog).0,
+            [
+                NewLeft,
+                MoveLeft,
+                LoopHead(7),
+                DeleteLeft,
+                MoveUp,
+                MoveUpToRoot,
+                Break(7),
+                LoopTail(2),
+                Break(9),
+            ],
+        );
+    }
+
+    #[test]
+    fn display_ops() {
+        for (name, prog) in corpus::programs() {
+            let code = Code::optimized(&prog);
+            let src = code.ops().iter().map(Op::to_string).collect::<String>();
+            assert_eq!(src, prog.to_string(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+
+use super::program::resolve_loops;
+use crate::{Inst, ParseError, Pos, Program, SourceMap};
+
+/// A lossless concrete syntax tree. Every byte of the source is kept, either as
+/// an instruction or as trivia (comments and whitespace) attached to the
+/// instruction that follows it, so it prints back to exactly the text it was
+/// parsed from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Cst {
+    tokens: Vec<Token>,
+    trailing: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Token {
+    /// The trivia before the instruction.
+    pub leading: String,
+    /// The instruction, with loop heads unresolved.
+    pub inst: Inst,
+    pub pos: Pos,
+}
+
+impl Cst {
+    pub fn parse(src: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut trivia = String::new();
+        let mut pos = Pos::START;
+        for ch in src.chars() {
+            if let Some(inst) = Inst::from_char(ch) {
+                tokens.push(Token {
+                    leading: std::mem::take(&mut trivia),
+                    inst,
+                    pos,
+                });
+            } else {
+                trivia.push(ch);
+            }
+            pos.advance(ch);
+        }
+        Cst {
+            tokens,
+            trailing: trivia,
+        }
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    pub fn tokens_mut(&mut self) -> &mut [Token] {
+        &mut self.tokens
+    }
+
+    /// The trivia after the last instruction.
+    pub fn trailing(&self) -> &str {
+        &self.trailing
+    }
+
+    pub fn trailing_mut(&mut self) -> &mut String {
+        &mut self.trailing
+    }
+
+    /// Lowers to a program, discarding trivia. Unmatched loop brackets are
+    /// dropped and reported, like [`Program::parse_recovering`].
+    pub fn lower(&self) -> (Program, SourceMap, Vec<ParseError>) {
+        let insts = self.tokens.iter().map(|tok| tok.inst).collect();
+        let positions = self.tokens.iter().map(|tok| tok.pos).collect();
+        resolve_loops(insts, Some(positions))
+    }
+}
+
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for tok in &self.tokens {
+            f.write_str(&tok.leading)?;
+            write!(f, "{}", tok.inst)?;
+        }
+        f.write_str(&self.trailing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus;
+
+    #[test]
+    fn print_corpus() {
+        for (name, src) in corpus::sources() {
+            let cst = Cst::parse(&src);
+            assert_eq!(cst.to_string(), src, "{name}");
+            assert_eq!(cst.lower(), Program::parse_recovering(&src), "{name}");
+        }
+    }
+
+    #[test]
+    fn print_trivia() {
+        let src = "\u{feff}# a comment\r\n\t+ é 🌿<\n\n)(unclosed\n  trailing \n";
+        let cst = Cst::parse(src);
+        assert_eq!(cst.to_string(), src);
+        assert_eq!(cst.tokens()[0].leading, "\u{feff}# a comment\r\n\t");
+        assert_eq!(cst.tokens()[1].leading, " é 🌿");
+        assert_eq!(cst.trailing(), "unclosed\n  trailing \n");
+        let (_, _, errors) = cst.lower();
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn print_empty() {
+        assert_eq!(Cst::parse("").to_string(), "");
+        assert_eq!(Cst::parse("no code\n").to_string(), "no code\n");
+    }
+}
@@ -0,0 +1,384 @@
+use crate::{Block, Cst, Inst, Program, Stmt};
+
+/// Options for [`Cst::format`] and [`Program::format`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FormatOptions {
+    /// Spaces per level of loop nesting.
+    pub indent: usize,
+    /// Column at which long lines of code are wrapped.
+    pub max_width: usize,
+    /// Lines with more code than this do not push out the comments of the
+    /// lines around them and instead stick out.
+    pub max_comment_align: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            indent: 2,
+            max_width: 80,
+            max_comment_align: 24,
+        }
+    }
+}
+
+/// Minimum space between code and a trailing comment.
+const COMMENT_GAP: usize = 2;
+/// Comments are aligned to a multiple of this past the indentation.
+const COMMENT_TAB: usize = 4;
+/// Comments are aligned to at least this far past the indentation.
+const MIN_COMMENT_COL: usize = 8;
+
+#[derive(Debug)]
+struct Line {
+    level: usize,
+    src_indent: usize,
+    /// Code from the first non-blank character through the last instruction.
+    code: String,
+    comment: String,
+    /// The column the comment started at in the source, used to recognize
+    /// comments continued on the following lines.
+    src_comment_col: Option<usize>,
+    /// The comment continues the one on the line above, offset by this much.
+    continues: Option<usize>,
+}
+
+impl Cst {
+    /// Formats the source canonically: loop bodies are indented by nesting
+    /// depth, trailing comments are aligned, and long lines of code are
+    /// wrapped. Lines of code are otherwise kept as written.
+    ///
+    /// Each line with a net opening of loops indents the following lines by a
+    /// single level, no matter how many loops it opens, and a line that closes
+    /// a loop is indented like the line that opened it. This lets chains like
+    /// `(^(?^(? …` and `<{>)>?` read as one level, as in `leaf.leaf`. Lines of
+    /// only comments are indented at least to their level, but may be indented
+    /// further to keep diagrams intact.
+    pub fn format(&self, opts: &FormatOptions) -> String {
+        let src = self.to_string();
+        let mut lines: Vec<Option<Line>> = Vec::new();
+        let mut levels: Vec<usize> = Vec::new();
+        let mut blank = false;
+        for text in src.lines() {
+            let chars = text.trim_end().chars().collect::<Vec<_>>();
+            let Some(first) = chars.iter().position(|ch| !ch.is_whitespace()) else {
+                blank = !lines.is_empty();
+                continue;
+            };
+            if blank {
+                lines.push(None);
+                blank = false;
+            }
+            let last = chars.iter().rposition(|&ch| Inst::from_char(ch).is_some());
+            let Some(last) = last else {
+                // A comment-only line starting at or past the column of the
+                // comment above continues it.
+                let comment = chars[first..].iter().collect();
+                let prev_col = match lines.last() {
+                    Some(Some(prev)) => prev.src_comment_col,
+                    _ => None,
+                };
+                let (continues, src_comment_col) = match prev_col {
+                    Some(col) if first >= col => (Some(first - col), Some(col)),
+                    _ => (None, None),
+                };
+                let level = levels.last().map_or(0, |level| level + 1);
+                lines.push(Some(Line {
+                    level,
+                    src_indent: first,
+                    code: String::new(),
+                    comment,
+                    src_comment_col,
+                    continues,
+                }));
+                continue;
+            };
+
+            let src_comment_col = chars[last + 1..]
+                .iter()
+                .position(|ch| !ch.is_whitespace())
+                .map(|i| last + 1 + i);
+            let mut comment = chars[last + 1..]
+                .iter()
+                .collect::<String>()
+                .trim()
+                .to_owned();
+            let code = chars[first..=last].iter().collect::<String>();
+            let mut code = code.as_str();
+            while !code.is_empty() {
+                // Lines are at most as indented as the loops open before them,
+                // but may be less.
+                let level = line_level(&mut levels.clone(), code);
+                let (chunk, rest) = if level * opts.indent + code.chars().count() <= opts.max_width
+                {
+                    (code, "")
+                } else {
+                    let start_level = levels.last().map_or(0, |level| level + 1);
+                    split_line(
+                        code,
+                        opts.max_width.saturating_sub(start_level * opts.indent),
+                    )
+                };
+                code = rest;
+                let comment = std::mem::take(&mut comment);
+                lines.push(Some(Line {
+                    level: line_level(&mut levels, chunk),
+                    src_indent: first,
+                    code: chunk.to_owned(),
+                    src_comment_col: src_comment_col.filter(|_| !comment.is_empty()),
+                    comment,
+                    continues: None,
+                }));
+            }
+        }
+
+        let mut out = String::with_capacity(src.len());
+        let mut prev_col = 0;
+        let mut i = 0;
+        while i < lines.len() {
+            let Some(line) = &lines[i] else {
+                out.push('\n');
+                i += 1;
+                continue;
+            };
+            if line.comment.is_empty() || line.code.is_empty() && line.continues.is_none() {
+                prev_col = write_line(&mut out, line, opts, 0, prev_col);
+                i += 1;
+                continue;
+            }
+            // Align the comments of a run of commented lines.
+            let mut j = i + 1;
+            while let Some(Some(next)) = lines.get(j) {
+                if next.comment.is_empty() || next.code.is_empty() && next.continues.is_none() {
+                    break;
+                }
+                j += 1;
+            }
+            let width = lines[i..j]
+                .iter()
+                .flatten()
+                .map(|line| line.code.chars().count())
+                .filter(|&width| width <= opts.max_comment_align)
+                .max()
+                .unwrap_or(0);
+            let col = (width + COMMENT_GAP)
+                .next_multiple_of(COMMENT_TAB)
+                .max(MIN_COMMENT_COL);
+            for line in lines[i..j].iter().flatten() {
+                prev_col = write_line(&mut out, line, opts, col, prev_col);
+            }
+            i = j;
+        }
+        out
+    }
+}
+
+impl Program {
+    /// Formats the program canonically. A loop is kept on one line when it
+    /// fits within the line width and otherwise has its body on its own lines.
+    pub fn format(&self, opts: &FormatOptions) -> String {
+        let mut src = String::new();
+        layout(&Block::from(self), 0, opts, &mut src);
+        Cst::parse(&src).format(opts)
+    }
+}
+
+/// Writes a line and returns the column its comment starts at, which any
+/// continuation lines are relative to.
+fn write_line(
+    out: &mut String,
+    line: &Line,
+    opts: &FormatOptions,
+    comment_col: usize,
+    prev_col: usize,
+) -> usize {
+    let indent = line.level * opts.indent;
+    if line.code.is_empty() {
+        let (col, base) = match line.continues {
+            Some(offset) => (prev_col + offset, prev_col),
+            None => (indent.max(line.src_indent), indent),
+        };
+        push_spaces(out, col);
+        out.push_str(&line.comment);
+        out.push('\n');
+        return base;
+    }
+    push_spaces(out, indent);
+    out.push_str(&line.code);
+    let width = line.code.chars().count();
+    let gap = if width + COMMENT_GAP <= comment_col {
+        comment_col - width
+    } else {
+        COMMENT_GAP
+    };
+    if !line.comment.is_empty() {
+        push_spaces(out, gap);
+        out.push_str(&line.comment);
+    }
+    out.push('\n');
+    indent + width + gap
+}
+
+fn push_spaces(out: &mut String, n: usize) {
+    out.extend((0..n).map(|_| ' '));
+}
+
+/// Computes the indentation level of a line and updates the levels of the
+/// loops open after it. `levels` holds the level of the line that opened each
+/// loop.
+fn line_level(levels: &mut Vec<usize>, line: &str) -> usize {
+    let mut level = levels.last().map_or(0, |level| level + 1);
+    let mut opened = 0;
+    for ch in line.chars() {
+        match Inst::from_char(ch) {
+            Some(Inst::LoopHead(_)) => opened += 1,
+            Some(Inst::LoopTail) => {
+                if opened > 0 {
+                    opened -= 1;
+                } else if let Some(open_level) = levels.pop() {
+                    level = level.min(open_level);
+                }
+            }
+            _ => {}
+        }
+    }
+    levels.extend((0..opened).map(|_| level));
+    level
+}
+
+/// Splits off the first line of code no wider than `width`, preferring to
+/// break at a space and otherwise breaking before an instruction. Lines only
+/// end in instructions, so that no code is mistaken for a trailing comment.
+fn split_line(code: &str, width: usize) -> (&str, &str) {
+    let Some((limit, _)) = code.char_indices().nth(width.max(1)) else {
+        return (code, "");
+    };
+    let is_inst = |ch: char| Inst::from_char(ch).is_some();
+    let splits = code.char_indices().filter(|&(i, ch)| {
+        i != 0 && (ch.is_whitespace() || is_inst(ch)) && code[..i].trim_end().ends_with(is_inst)
+    });
+    let split = splits
+        .clone()
+        .rfind(|&(i, ch)| i <= limit && ch.is_whitespace())
+        .or_else(|| splits.clone().rfind(|&(i, _)| i <= limit))
+        .or_else(|| splits.clone().find(|&(i, _)| i > limit));
+    match split {
+        Some((i, _)) => (code[..i].trim_end(), code[i..].trim_start()),
+        None => (code, ""),
+    }
+}
+
+/// Lays out a block with one statement sequence per line, breaking loops that
+/// do not fit.
+fn layout(block: &Block, level: usize, opts: &FormatOptions, out: &mut String) {
+    let width = opts.max_width.saturating_sub(level * opts.indent);
+    let mut line = String::new();
+    for stmt in block.stmts() {
+        let mut inline = String::new();
+        push_stmt(stmt, &mut inline);
+        if line.len() + inline.len() <= width {
+            line.push_str(&inline);
+            continue;
+        }
+        if !line.is_empty() {
+            out.push_str(&line);
+            out.push('\n');
+            line.clear();
+        }
+        match stmt {
+            Stmt::Loop(body) if inline.len() > width => {
+                out.push_str("(\n");
+                layout(body, level + 1, opts, out);
+                line.push(')');
+            }
+            _ => line = inline,
+        }
+    }
+    if !line.is_empty() {
+        out.push_str(&line);
+        out.push('\n');
+    }
+}
+
+fn push_stmt(stmt: &Stmt, out: &mut String) {
+    match stmt {
+        Stmt::MoveLeft => out.push('<'),
+        Stmt::MoveRight => out.push('>'),
+        Stmt::MoveUp => out.push('^'),
+        Stmt::PushRoot => out.push('{'),
+        Stmt::PopRoot => out.push('}'),
+        Stmt::Loop(body) => {
+            out.push('(');
+            for stmt in body.stmts() {
+                push_stmt(stmt, out);
+            }
+            out.push(')');
+        }
+        Stmt::NewLeft => out.push('+'),
+        Stmt::NewRight => out.push('*'),
+        Stmt::Delete => out.push('-'),
+        Stmt::Break => out.push('?'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus;
+
+    const OPTS: [FormatOptions; 3] = [
+        FormatOptions {
+            indent: 2,
+            max_width: 80,
+            max_comment_align: 24,
+        },
+        FormatOptions {
+            indent: 4,
+            max_width: 40,
+            max_comment_align: 24,
+        },
+        FormatOptions {
+            indent: 1,
+            max_width: 10,
+            max_comment_align: 4,
+        },
+    ];
+
+    #[test]
+    fn idempotent() {
+        for (name, src) in corpus::sources() {
+            let prog = Program::parse(&src).unwrap();
+            for opts in &OPTS {
+                let formatted = Cst::parse(&src).format(opts);
+                assert_eq!(Cst::parse(&formatted).format(opts), formatted, "{name}");
+                assert_eq!(Program::parse(&formatted).unwrap(), prog, "{name}");
+
+                let formatted = prog.format(opts);
+                assert_eq!(Cst::parse(&formatted).format(opts), formatted, "{name}");
+                assert_eq!(Program::parse(&formatted).unwrap(), prog, "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn tutorial_is_formatted() {
+        for (name, src) in corpus::sources() {
+            if name.starts_with("tutorial") {
+                assert_eq!(
+                    Cst::parse(&src).format(&FormatOptions::default()),
+                    src,
+                    "{name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn indent_loops() {
+        let src = "+(\n<(>\n# note\n-)  # end\n)\n";
+        let formatted = Cst::parse(src).format(&FormatOptions::default());
+        // `-)` closes the loop opened on the line of `<(>`, so is indented
+        // like it, and its comment is aligned to the minimum column.
+        assert_eq!(formatted, "+(\n  <(>\n    # note\n  -)      # end\n)\n");
+    }
+}
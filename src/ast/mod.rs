@@ -0,0 +1,13 @@
+mod block;
+mod code;
+mod cst;
+mod format;
+mod program;
+mod span;
+
+pub use block::*;
+pub(crate) use code::*;
+pub use cst::*;
+pub use format::*;
+pub use program::*;
+pub use span::*;
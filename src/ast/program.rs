@@ -0,0 +1,400 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::{Pos, SourceMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Inst {
+    /// `<`
+    MoveLeft,
+    /// `>`
+    MoveRight,
+    /// `^`
+    MoveUp,
+    /// `{`
+    PushRoot,
+    /// `}`
+    PopRoot,
+    /// `(`
+    LoopHead(usize),
+    /// `)`
+    LoopTail,
+    /// `+`
+    NewLeft,
+    /// `*`
+    NewRight,
+    /// `-`
+    Delete,
+    /// `?`
+    Break,
+}
+
+/// A program with matched loops. Programs are usually built by parsing, but can
+/// also borrow static instructions, which lets them be constants.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Program(pub(crate) Cow<'static, [Inst]>);
+
+/// An unmatched loop bracket. Programs parsed from source report the position
+/// of the offending bracket and programs built from instructions report its
+/// index.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("unopened loop (`)` without `(`) at {0}")]
+    UnopenedLoop(Pos),
+    #[error("unclosed loop (`(` without `)`) at {0}")]
+    UnclosedLoop(Pos),
+    #[error("unopened loop (`)` without `(`) at instruction {0}")]
+    UnopenedLoopInst(usize),
+    #[error("unclosed loop (`(` without `)`) at instruction {0}")]
+    UnclosedLoopInst(usize),
+}
+
+impl Program {
+    pub fn parse(src: &str) -> Result<Self, ParseError> {
+        Program::parse_with_source_map(src).map(|(prog, _)| prog)
+    }
+
+    pub fn parse_with_source_map(src: &str) -> Result<(Self, SourceMap), ParseError> {
+        let (prog, map, errors) = Program::parse_recovering(src);
+        match errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok((prog, map)),
+        }
+    }
+
+    /// Parses a program, recovering from unmatched loop brackets by dropping
+    /// them. Every such bracket is reported, ordered by position, and the
+    /// returned program is always well-formed.
+    pub fn parse_recovering(src: &str) -> (Self, SourceMap, Vec<ParseError>) {
+        let mut insts = Vec::new();
+        let mut positions = Vec::new();
+        let mut pos = Pos::START;
+        for ch in src.chars() {
+            if let Some(inst) = Inst::from_char(ch) {
+                insts.push(inst);
+                positions.push(pos);
+            }
+            pos.advance(ch);
+        }
+        resolve_loops(insts, Some(positions))
+    }
+
+    pub fn from_insts(prog: Vec<Inst>) -> Result<Self, ParseError> {
+        let (prog, _, errors) = resolve_loops(prog, None);
+        match errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(prog),
+        }
+    }
+
+    /// Borrows static instructions as a program, without allocating. Unlike
+    /// [`Program::from_insts`], loop heads must already be resolved to their
+    /// tails. It is `const`, so that `leaf!` can expand to a constant.
+    ///
+    /// # Panics
+    ///
+    /// Panics, which fails compilation in a const context, if a loop is
+    /// unmatched or a loop head is not resolved to its tail.
+    pub const fn from_static(insts: &'static [Inst]) -> Self {
+        match check_loops(insts, 0) {
+            Ok(pc) if pc == insts.len() => Program(Cow::Borrowed(insts)),
+            Ok(_) => panic!("unopened loop (`)` without `(`)"),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    pub fn insts(&self) -> &[Inst] {
+        &self.0
+    }
+
+    pub fn get(&self, pc: usize) -> Option<&Inst> {
+        self.0.get(pc)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Inst {
+    /// Decodes a command character. Loop heads are left unresolved.
+    pub fn from_char(ch: char) -> Option<Self> {
+        Some(match ch {
+            '<' => Inst::MoveLeft,
+            '>' => Inst::MoveRight,
+            '^' => Inst::MoveUp,
+            '{' => Inst::PushRoot,
+            '}' => Inst::PopRoot,
+            '(' => Inst::LoopHead(usize::MAX),
+            ')' => Inst::LoopTail,
+            '+' => Inst::NewLeft,
+            '*' => Inst::NewRight,
+            '-' => Inst::Delete,
+            '?' => Inst::Break,
+            _ => return None,
+        })
+    }
+
+    /// Encodes as a command character.
+    pub fn to_char(self) -> char {
+        match self {
+            Inst::MoveLeft => '<',
+            Inst::MoveRight => '>',
+            Inst::MoveUp => '^',
+            Inst::PushRoot => '{',
+            Inst::PopRoot => '}',
+            Inst::LoopHead(_) => '(',
+            Inst::LoopTail => ')',
+            Inst::NewLeft => '+',
+            Inst::NewRight => '*',
+            Inst::Delete => '-',
+            Inst::Break => '?',
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_char())
+    }
+}
+
+/// Prints the program as canonical source, with one character per instruction
+/// and no whitespace, which parses back to an equal program.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for inst in self.0.iter() {
+            write!(f, "{inst}")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Program> for Vec<Inst> {
+    fn from(prog: Program) -> Self {
+        prog.0.into_owned()
+    }
+}
+
+impl ParseError {
+    /// Returns the position of the bracket, for programs parsed from source.
+    pub fn pos(&self) -> Option<Pos> {
+        match *self {
+            ParseError::UnopenedLoop(pos) | ParseError::UnclosedLoop(pos) => Some(pos),
+            ParseError::UnopenedLoopInst(_) | ParseError::UnclosedLoopInst(_) => None,
+        }
+    }
+}
+
+/// Matches loop heads with tails, dropping and reporting any unmatched ones.
+/// Errors are located by `positions`, when the program has a source, or else
+/// by instruction index.
+pub(super) fn resolve_loops(
+    insts: Vec<Inst>,
+    positions: Option<Vec<Pos>>,
+) -> (Program, SourceMap, Vec<ParseError>) {
+    let mut matched = vec![true; insts.len()];
+    let mut unmatched = Vec::new();
+    let mut loops = Vec::new();
+    for (pc, inst) in insts.iter().enumerate() {
+        match inst {
+            Inst::LoopHead(_) => loops.push(pc),
+            Inst::LoopTail if loops.pop().is_none() => unmatched.push(pc),
+            _ => {}
+        }
+    }
+    unmatched.append(&mut loops);
+    unmatched.sort();
+    let mut errors = Vec::with_capacity(unmatched.len());
+    for pc in unmatched {
+        matched[pc] = false;
+        let unopened = insts[pc] == Inst::LoopTail;
+        errors.push(match (&positions, unopened) {
+            (Some(positions), true) => ParseError::UnopenedLoop(positions[pc]),
+            (Some(positions), false) => ParseError::UnclosedLoop(positions[pc]),
+            (None, true) => ParseError::UnopenedLoopInst(pc),
+            (None, false) => ParseError::UnclosedLoopInst(pc),
+        });
+    }
+
+    let mut prog = Vec::with_capacity(insts.len());
+    let mut map = SourceMap::new();
+    for (pc, inst) in insts.into_iter().enumerate() {
+        if !matched[pc] {
+            continue;
+        }
+        match inst {
+            Inst::LoopHead(_) => loops.push(prog.len()),
+            Inst::LoopTail => {
+                let head = loops.pop().unwrap();
+                prog[head] = Inst::LoopHead(prog.len());
+            }
+            _ => {}
+        }
+        prog.push(inst);
+        if let Some(positions) = &positions {
+            map.push(positions[pc]);
+        }
+    }
+    (Program(prog.into()), map, errors)
+}
+
+/// Checks the loops in a block starting at `pc`, recursing into nested loops,
+/// and returns the index of the tail that ends it, or the end of the program.
+/// Each loop head must be resolved to its tail.
+const fn check_loops(insts: &[Inst], mut pc: usize) -> Result<usize, &'static str> {
+    while pc < insts.len() {
+        match insts[pc] {
+            Inst::LoopHead(tail) => match check_loops(insts, pc + 1) {
+                Ok(end) if end == insts.len() => return Err("unclosed loop (`(` without `)`)"),
+                Ok(end) if end != tail => return Err("loop head is not resolved to its tail"),
+                Ok(end) => pc = end,
+                Err(err) => return Err(err),
+            },
+            Inst::LoopTail => return Ok(pc),
+            _ => {}
+        }
+        pc += 1;
+    }
+    Ok(pc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(offset: usize, line: usize, col: usize) -> Pos {
+        Pos { offset, line, col }
+    }
+
+    #[test]
+    fn unclosed_loop() {
+        let err = Program::parse("+\n*(<(>)").unwrap_err();
+        assert_eq!(err, ParseError::UnclosedLoop(pos(3, 2, 2)));
+    }
+
+    #[test]
+    fn unopened_loop() {
+        let err = Program::parse("(+)\n  ^)").unwrap_err();
+        assert_eq!(err, ParseError::UnopenedLoop(pos(7, 2, 4)));
+    }
+
+    #[test]
+    fn multibyte_before_error() {
+        // `é` is 2 bytes and `🌿` is 4, but each is one column.
+        let err = Program::parse("é🌿 +)").unwrap_err();
+        assert_eq!(err, ParseError::UnopenedLoop(pos(8, 1, 5)));
+        let err = Program::parse("+\n🌿é(").unwrap_err();
+        assert_eq!(err, ParseError::UnclosedLoop(pos(8, 2, 3)));
+    }
+
+    #[test]
+    fn source_map() {
+        let (prog, map) = Program::parse_with_source_map("# é\n(+)").unwrap();
+        assert_eq!(prog.len(), 3);
+        assert_eq!(map.positions(), [pos(5, 2, 1), pos(6, 2, 2), pos(7, 2, 3)]);
+    }
+
+    #[test]
+    fn excerpt() {
+        let src = "+<\n\té ^)\n*";
+        let err = Program::parse(src).unwrap_err();
+        assert_eq!(err.pos(), Some(pos(8, 2, 5)));
+        assert_eq!(
+            err.pos().unwrap().excerpt(src).to_string(),
+            "  |\n2 | \té ^)\n  | \t   ^\n",
+        );
+    }
+
+    #[test]
+    fn from_insts_index() {
+        use Inst::*;
+        let err = Program::from_insts(vec![NewLeft, LoopTail]).unwrap_err();
+        assert_eq!(err, ParseError::UnopenedLoopInst(1));
+        assert_eq!(err.pos(), None);
+        assert_eq!(
+            err.to_string(),
+            "unopened loop (`)` without `(`) at instruction 1",
+        );
+        let err = Program::from_insts(vec![LoopHead(0), LoopHead(0), LoopTail]).unwrap_err();
+        assert_eq!(err, ParseError::UnclosedLoopInst(0));
+    }
+
+    #[test]
+    fn from_static() {
+        use Inst::*;
+        const PROG: Program = Program::from_static(&[LoopHead(3), NewLeft, Break, LoopTail]);
+        assert_eq!(PROG, Program::parse("(+?)").unwrap());
+        assert!(matches!(PROG.0, Cow::Borrowed(_)));
+        assert_eq!(Program::from_static(&[]), Program::parse("").unwrap());
+    }
+
+    #[test]
+    #[should_panic = "unopened loop"]
+    fn from_static_unopened() {
+        Program::from_static(&[Inst::NewLeft, Inst::LoopTail]);
+    }
+
+    #[test]
+    #[should_panic = "unclosed loop"]
+    fn from_static_unclosed() {
+        use Inst::*;
+        Program::from_static(&[LoopHead(1), LoopTail, LoopHead(3)]);
+    }
+
+    #[test]
+    #[should_panic = "not resolved"]
+    fn from_static_unresolved() {
+        use Inst::*;
+        Program::from_static(&[LoopHead(2), LoopHead(3), LoopTail, LoopTail]);
+    }
+
+    #[test]
+    fn recover_all_errors() {
+        let (prog, map, errors) = Program::parse_recovering("(+)\n)(<\n>))(");
+        assert_eq!(
+            errors,
+            [
+                ParseError::UnopenedLoop(pos(4, 2, 1)),
+                ParseError::UnopenedLoop(pos(10, 3, 3)),
+                ParseError::UnclosedLoop(pos(11, 3, 4)),
+            ],
+        );
+        // The unmatched brackets are dropped and the rest stay matched.
+        assert_eq!(prog, Program::parse("(+)(<>)").unwrap());
+        assert_eq!(map.pos(3), Some(pos(5, 2, 2)));
+        assert_eq!(map.pos(6), Some(pos(9, 3, 2)));
+    }
+
+    #[test]
+    fn recover_nested_unclosed() {
+        let (prog, _, errors) = Program::parse_recovering("((?(");
+        assert_eq!(
+            errors,
+            [
+                ParseError::UnclosedLoop(pos(0, 1, 1)),
+                ParseError::UnclosedLoop(pos(1, 1, 2)),
+                ParseError::UnclosedLoop(pos(3, 1, 4)),
+            ],
+        );
+        assert_eq!(prog.insts(), [Inst::Break]);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for (name, prog) in crate::corpus::programs() {
+            let src = prog.to_string();
+            assert!(
+                src.chars().all(|ch| Inst::from_char(ch).is_some()),
+                "{name}"
+            );
+            assert_eq!(Program::parse(&src).unwrap(), prog, "{name}");
+        }
+    }
+}
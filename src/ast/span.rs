@@ -0,0 +1,95 @@
+use std::fmt;
+
+/// A location in source text. Lines and columns are 1-based and columns count
+/// characters, not bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Pos {
+    pub offset: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+/// Maps the instructions of a parsed program back to where they occur in the
+/// source.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    positions: Vec<Pos>,
+}
+
+/// The source line containing a position, with a caret under it.
+#[derive(Clone, Copy, Debug)]
+pub struct Excerpt<'a> {
+    src: &'a str,
+    pos: Pos,
+}
+
+impl Pos {
+    pub const START: Pos = Pos {
+        offset: 0,
+        line: 1,
+        col: 1,
+    };
+
+    /// Advances past `ch`.
+    pub fn advance(&mut self, ch: char) {
+        self.offset += ch.len_utf8();
+        if ch == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+    }
+
+    pub fn excerpt(self, src: &str) -> Excerpt<'_> {
+        Excerpt { src, pos: self }
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap {
+            positions: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, pos: Pos) {
+        self.positions.push(pos);
+    }
+
+    /// Returns the position of the instruction at `pc`.
+    pub fn pos(&self, pc: usize) -> Option<Pos> {
+        self.positions.get(pc).copied()
+    }
+
+    pub fn positions(&self) -> &[Pos] {
+        &self.positions
+    }
+}
+
+impl fmt::Display for Excerpt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self.pos.offset.min(self.src.len());
+        let start = self.src[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = self.src[offset..]
+            .find('\n')
+            .map_or(self.src.len(), |i| offset + i);
+        let line = self.src[start..end].trim_end_matches('\r');
+        let gutter = self.pos.line.to_string();
+        let pad = " ".repeat(gutter.len());
+        writeln!(f, "{pad} |")?;
+        writeln!(f, "{gutter} | {line}")?;
+        // Keep tabs so the caret lines up with the excerpt.
+        let indent: String = self.src[start..offset]
+            .chars()
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(f, "{pad} | {indent}^")
+    }
+}
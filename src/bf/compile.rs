@@ -0,0 +1,251 @@
+use crate::{BfError, Pos, Program};
+
+/// Options for compiling Brainfuck to Leaf, following `brainfuck.md`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BfOptions {
+    pub tape: Tape,
+    /// Cells wrap around between 0 and 255, instead of stopping at 0 and
+    /// growing without bound.
+    pub wrapping: bool,
+    pub io: IoHandling,
+}
+
+/// The layout of the Brainfuck tape. Cells are a right spine from the root,
+/// with the current cell as the top root and its value as the length of the
+/// chain to its left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Tape {
+    /// A fixed number of cells, allocated up front. Moving past either end
+    /// stays at the edge.
+    Bounded(usize),
+    /// Cells allocated as the tape is extended to the right. Moving left of
+    /// the first cell stays there.
+    Unbounded,
+}
+
+/// How to treat `,` and `.`, which have no equivalent in Leaf.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoHandling {
+    /// Reject programs that use I/O.
+    Error,
+    /// Drop I/O commands.
+    Ignore,
+}
+
+impl Default for BfOptions {
+    fn default() -> Self {
+        BfOptions {
+            tape: Tape::Unbounded,
+            wrapping: false,
+            io: IoHandling::Error,
+        }
+    }
+}
+
+/// Compiles a Brainfuck program to Leaf.
+pub fn compile_bf(src: &str, opts: &BfOptions) -> Result<Program, BfError> {
+    let leaf = compile_bf_source(src, opts)?;
+    Ok(Program::parse(&leaf).expect("compiled loops are balanced"))
+}
+
+/// Compiles a Brainfuck program to Leaf source, with one command per line and
+/// loop bodies indented.
+pub fn compile_bf_source(src: &str, opts: &BfOptions) -> Result<String, BfError> {
+    let mut out = String::new();
+    match opts.tape {
+        Tape::Bounded(0) => return Err(BfError::EmptyTape),
+        Tape::Bounded(1) => out.push_str("{\n"),
+        Tape::Bounded(cells) => {
+            out.push_str(&"*>".repeat(cells - 2));
+            out.push_str("*\n(^){\n");
+        }
+        Tape::Unbounded => out.push_str("*{\n"),
+    }
+    out.push('\n');
+
+    let (inc, dec) = if opts.wrapping {
+        let inc = format!("(<)+{}(?-)", "^".repeat(254));
+        let dec = format!("(<)+(?-<){{<(?{}+>)}}(^)", "+<".repeat(253));
+        (inc, dec)
+    } else {
+        ("(<)+(^)".to_owned(), "(<)(?-(^))".to_owned())
+    };
+    let right = match opts.tape {
+        Tape::Bounded(_) => "}>{",
+        Tape::Unbounded => "}>{(>)^*(^)",
+    };
+
+    let mut loops = Vec::new();
+    let mut pos = Pos::START;
+    for ch in src.chars() {
+        let code = match ch {
+            '>' => right,
+            '<' => "}^{",
+            '+' => &inc,
+            '-' => &dec,
+            '[' => {
+                loops.push(pos);
+                "(<?^"
+            }
+            ']' => {
+                if loops.pop().is_none() {
+                    return Err(BfError::UnopenedLoop(pos));
+                }
+                ")"
+            }
+            ',' | '.' if opts.io == IoHandling::Error => return Err(BfError::Io(ch, pos)),
+            _ => "",
+        };
+        pos.advance(ch);
+        if code.is_empty() {
+            continue;
+        }
+        let depth = if ch == '[' {
+            loops.len() - 1
+        } else {
+            loops.len()
+        };
+        out.extend((0..depth * 2).map(|_| ' '));
+        out.push_str(code);
+        out.push('\n');
+    }
+    if let Some(&pos) = loops.first() {
+        return Err(BfError::UnclosedLoop(pos));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{corpus, BfMachine, VM};
+
+    /// The Brainfuck programs translated under `programs/brainfuck/`, with
+    /// whether they rely on wrapping cells.
+    const EXAMPLES: [(&str, &str, bool); 3] = [
+        ("brainfuck/42_nowrap.leaf", "++++++[>+++++++<-]>", false),
+        ("brainfuck/42_wrap.leaf", "--[>+<++++++]>-", true),
+        ("brainfuck/beaver_17.leaf", ">+[>++>+++[-<]>>]", false),
+    ];
+
+    fn run_leaf(prog: Program) -> (Vec<usize>, usize) {
+        let mut vm = VM::new(prog);
+        vm.run().unwrap();
+        let tape = vm.tree().decode_bf_tape().unwrap();
+        (tape.cells, tape.current)
+    }
+
+    fn run_bf(src: &str, wrapping: bool) -> (Vec<usize>, usize) {
+        let mut bf = BfMachine::new(src, if wrapping { 8 } else { 64 }).unwrap();
+        bf.run().unwrap();
+        let mut cells: Vec<usize> = bf.tape().iter().map(|&cell| cell as usize).collect();
+        while cells.len() > bf.ptr() + 1 && cells.last() == Some(&0) {
+            cells.pop();
+        }
+        (cells, bf.ptr())
+    }
+
+    #[test]
+    fn compile_examples() {
+        let sources = corpus::sources();
+        for (name, bf, wrapping) in EXAMPLES {
+            let (_, leaf) = sources.iter().find(|(n, _)| n == name).unwrap();
+            assert!(leaf.contains(bf), "{name}");
+            let opts = BfOptions {
+                wrapping,
+                ..BfOptions::default()
+            };
+            let expected = run_bf(bf, wrapping);
+            let compiled = compile_bf(bf, &opts).unwrap();
+            assert_eq!(run_leaf(compiled), expected, "{name}");
+            assert_eq!(run_leaf(Program::parse(leaf).unwrap()), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn compile_left_of_first_cell() {
+        // Both stay at the first cell.
+        let bf = "+<<+>+<<<";
+        let expected = run_bf(bf, false);
+        assert_eq!(expected, (vec![2, 1], 0));
+        let compiled = compile_bf(bf, &BfOptions::default()).unwrap();
+        assert_eq!(run_leaf(compiled), expected);
+    }
+
+    #[test]
+    fn compile_source() {
+        // The beaver was translated with exactly these rules.
+        let (_, leaf) = corpus::sources()
+            .into_iter()
+            .find(|(name, _)| name == "brainfuck/beaver_17.leaf")
+            .unwrap();
+        let src = compile_bf_source(">+[>++>+++[-<]>>]", &BfOptions::default()).unwrap();
+        assert!(leaf.ends_with(&src));
+    }
+
+    #[test]
+    fn compile_bounded() {
+        let opts = BfOptions {
+            tape: Tape::Bounded(3),
+            ..BfOptions::default()
+        };
+        assert_eq!(compile_bf_source("", &opts).unwrap(), "*>*\n(^){\n\n");
+        // Moves stop at either end of the tape.
+        let prog = compile_bf("<+>>>>++", &opts).unwrap();
+        assert_eq!(run_leaf(prog), (vec![1, 0, 2], 2));
+        let opts = BfOptions {
+            tape: Tape::Bounded(0),
+            ..BfOptions::default()
+        };
+        assert_eq!(compile_bf("+", &opts), Err(BfError::EmptyTape));
+    }
+
+    #[test]
+    fn compile_errors() {
+        let opts = BfOptions::default();
+        assert_eq!(
+            compile_bf("+\n[[]", &opts),
+            Err(BfError::UnclosedLoop(Pos {
+                offset: 2,
+                line: 2,
+                col: 1,
+            })),
+        );
+        assert_eq!(
+            compile_bf("+]", &opts),
+            Err(BfError::UnopenedLoop(Pos {
+                offset: 1,
+                line: 1,
+                col: 2,
+            })),
+        );
+        assert_eq!(
+            compile_bf("+.", &opts),
+            Err(BfError::Io(
+                '.',
+                Pos {
+                    offset: 1,
+                    line: 1,
+                    col: 2,
+                },
+            )),
+        );
+        let opts = BfOptions {
+            io: IoHandling::Ignore,
+            ..opts
+        };
+        assert_eq!(compile_bf(",+.", &opts), compile_bf("+", &opts));
+    }
+
+    #[test]
+    fn compile_wrapping() {
+        let opts = BfOptions {
+            wrapping: true,
+            ..BfOptions::default()
+        };
+        let prog = compile_bf("->-->+-", &opts).unwrap();
+        assert_eq!(run_leaf(prog), (vec![255, 254, 0], 2));
+        let prog = compile_bf("->-->+-", &BfOptions::default()).unwrap();
+        assert_eq!(run_leaf(prog), (vec![0, 0, 0], 2));
+    }
+}
@@ -0,0 +1,19 @@
+use thiserror::Error;
+
+use crate::Pos;
+
+/// An error from compiling Brainfuck, or parsing it to run with
+/// [`BfMachine`](crate::BfMachine).
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum BfError {
+    #[error("unopened loop (`]` without `[`) at {0}")]
+    UnopenedLoop(Pos),
+    #[error("unclosed loop (`[` without `]`) at {0}")]
+    UnclosedLoop(Pos),
+    #[error("unsupported I/O (`{0}`) at {1}")]
+    Io(char, Pos),
+    #[error("tape has no cells")]
+    EmptyTape,
+    #[error("cells of {0} bits are unsupported (must be between 1 and 64)")]
+    CellBits(u32),
+}
@@ -0,0 +1,211 @@
+use thiserror::Error;
+
+use crate::{BfError, Pos, RunStatus};
+
+/// A Brainfuck interpreter with a tape unbounded to the right and wrapping
+/// cells of a configurable width. Moving left of the first cell stays there,
+/// like the unbounded tape of [`compile_bf`](crate::compile_bf).
+#[derive(Clone, Debug)]
+pub struct BfMachine {
+    insts: Vec<BfInst>,
+    pc: usize,
+    tape: Vec<u64>,
+    ptr: usize,
+    mask: u64,
+    input: Vec<u8>,
+    input_pos: usize,
+    output: Vec<u8>,
+}
+
+/// A Brainfuck instruction, with runs of `+`, `-`, `>`, and `<` combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum BfInst {
+    Add(u64),
+    Right(usize),
+    Left(usize),
+    /// `[`, with the index of its `]`
+    Open(usize),
+    /// `]`, with the index of its `[`
+    Close(usize),
+    In,
+    Out,
+}
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum BfRunError {
+    #[error("Brainfuck program has terminated")]
+    Terminated,
+}
+
+impl BfMachine {
+    /// Parses a Brainfuck program, with cells of `cell_bits` bits, which must
+    /// be between 1 and 64.
+    pub fn new(src: &str, cell_bits: u32) -> Result<Self, BfError> {
+        if !(1..=64).contains(&cell_bits) {
+            return Err(BfError::CellBits(cell_bits));
+        }
+        let mut insts = Vec::new();
+        let mut loops = Vec::new();
+        let mut pos = Pos::START;
+        for ch in src.chars() {
+            let inst = match ch {
+                '+' => BfInst::Add(1),
+                '-' => BfInst::Add(u64::MAX),
+                '>' => BfInst::Right(1),
+                '<' => BfInst::Left(1),
+                '[' => {
+                    loops.push((insts.len(), pos));
+                    BfInst::Open(usize::MAX)
+                }
+                ']' => {
+                    let Some((head, _)) = loops.pop() else {
+                        return Err(BfError::UnopenedLoop(pos));
+                    };
+                    insts[head] = BfInst::Open(insts.len());
+                    BfInst::Close(head)
+                }
+                ',' => BfInst::In,
+                '.' => BfInst::Out,
+                _ => {
+                    pos.advance(ch);
+                    continue;
+                }
+            };
+            pos.advance(ch);
+            match (insts.last_mut(), inst) {
+                (Some(BfInst::Add(n)), BfInst::Add(m)) => *n = n.wrapping_add(m),
+                (Some(BfInst::Right(n)), BfInst::Right(m)) => *n += m,
+                (Some(BfInst::Left(n)), BfInst::Left(m)) => *n += m,
+                _ => insts.push(inst),
+            }
+        }
+        if let Some(&(_, pos)) = loops.first() {
+            return Err(BfError::UnclosedLoop(pos));
+        }
+        Ok(BfMachine {
+            insts,
+            pc: 0,
+            tape: vec![0],
+            ptr: 0,
+            mask: u64::MAX >> (64 - cell_bits),
+            input: Vec::new(),
+            input_pos: 0,
+            output: Vec::new(),
+        })
+    }
+
+    /// Sets the bytes read by `,`. At the end of input, `,` leaves the cell
+    /// unchanged.
+    pub fn set_input(&mut self, input: Vec<u8>) {
+        self.input = input;
+        self.input_pos = 0;
+    }
+
+    pub fn run(&mut self) -> Result<(), BfRunError> {
+        if self.pc >= self.insts.len() {
+            return Err(BfRunError::Terminated);
+        }
+        while self.pc < self.insts.len() {
+            self.step_inline()?;
+        }
+        Ok(())
+    }
+
+    /// Runs for at most `steps` instructions, counting each run of repeated
+    /// commands as one, and stops early when the program finishes or errors.
+    pub fn run_for(&mut self, steps: u64) -> Result<RunStatus, BfRunError> {
+        if self.pc >= self.insts.len() {
+            return Err(BfRunError::Terminated);
+        }
+        for _ in 0..steps {
+            if self.pc >= self.insts.len() {
+                return Ok(RunStatus::Finished);
+            }
+            self.step_inline()?;
+        }
+        if self.pc >= self.insts.len() {
+            Ok(RunStatus::Finished)
+        } else {
+            Ok(RunStatus::OutOfFuel)
+        }
+    }
+
+    #[inline(always)]
+    fn step_inline(&mut self) -> Result<(), BfRunError> {
+        let Some(&inst) = self.insts.get(self.pc) else {
+            return Err(BfRunError::Terminated);
+        };
+        match inst {
+            BfInst::Add(n) => {
+                let cell = &mut self.tape[self.ptr];
+                *cell = cell.wrapping_add(n) & self.mask;
+            }
+            BfInst::Right(n) => {
+                self.ptr += n;
+                if self.ptr >= self.tape.len() {
+                    self.tape.resize(self.ptr + 1, 0);
+                }
+            }
+            BfInst::Left(n) => {
+                self.ptr = self.ptr.saturating_sub(n);
+            }
+            BfInst::Open(tail) => {
+                if self.tape[self.ptr] == 0 {
+                    self.pc = tail;
+                }
+            }
+            BfInst::Close(head) => {
+                if self.tape[self.ptr] != 0 {
+                    self.pc = head;
+                }
+            }
+            BfInst::In => {
+                if let Some(&b) = self.input.get(self.input_pos) {
+                    self.tape[self.ptr] = b as u64 & self.mask;
+                    self.input_pos += 1;
+                }
+            }
+            BfInst::Out => self.output.push(self.tape[self.ptr] as u8),
+        }
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Returns the tape, up to the rightmost cell visited.
+    pub fn tape(&self) -> &[u64] {
+        &self.tape
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cell_bits() {
+        assert_eq!(BfMachine::new("", 0).unwrap_err(), BfError::CellBits(0));
+        assert_eq!(BfMachine::new("", 65).unwrap_err(), BfError::CellBits(65));
+        let mut bf = BfMachine::new("-", 8).unwrap();
+        bf.run().unwrap();
+        assert_eq!(bf.tape(), [255]);
+        let mut bf = BfMachine::new("-", 64).unwrap();
+        bf.run().unwrap();
+        assert_eq!(bf.tape(), [u64::MAX]);
+    }
+
+    #[test]
+    fn move_left_of_first_cell() {
+        let mut bf = BfMachine::new(">+<<<+>", 8).unwrap();
+        bf.run().unwrap();
+        assert_eq!(bf.tape(), [1, 1]);
+        assert_eq!(bf.ptr(), 1);
+    }
+}
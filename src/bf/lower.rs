@@ -0,0 +1,466 @@
+use thiserror::Error;
+
+use crate::tree::{MultiTree, RootedTree};
+use crate::{Block, Program, Stmt};
+
+// The tape starts with registers, followed by a loop flag for each level of
+// loop nesting, then the arena of node records. Record 0 is a gutter, which
+// is used to carry addresses and values to and from the other records.
+//
+// Nodes are allocated by bumping `NEXT` and never freed. A node is a root when
+// its root count is non-zero, since roots are always ancestors of the cursor.
+
+/// Temporary for copies.
+const T0: usize = 0;
+/// Temporaries for conditions.
+const T1: usize = 1;
+const T2: usize = 2;
+const T3: usize = 3;
+/// Temporary for guarding instructions after a break.
+const GUARD: usize = 4;
+const CUR: usize = 5;
+const SUCCESS: usize = 6;
+/// Cleared when a `?` breaks, until the end of its loop.
+const ACTIVE: usize = 7;
+const NROOTS: usize = 8;
+const NEXT: usize = 9;
+const X: usize = 10;
+const V: usize = 11;
+const W: usize = 12;
+const LOOP_FLAGS: usize = 13;
+
+// Fields of a node record.
+/// Breadcrumb marking the way back to the gutter.
+const CRUMB: usize = 0;
+/// The number of records left to travel.
+const COUNT: usize = 1;
+/// A value carried while travelling.
+const PAYLOAD: usize = 2;
+const LEFT: usize = 3;
+const RIGHT: usize = 4;
+const PARENT: usize = 5;
+const ROOTS: usize = 6;
+/// 1 for a left child and 2 for a right child.
+const SIDE: usize = 7;
+const RECORD: usize = 8;
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum BfStateError {
+    #[error("invalid node {0}")]
+    InvalidNode(u64),
+    #[error("cursor is not in the tree")]
+    InvalidCursor,
+    #[error("invalid root stack")]
+    InvalidRootStack,
+}
+
+impl Program {
+    /// Compiles the program to Brainfuck, which simulates the tree, cursor
+    /// and root stack on the tape, with Leafy semantics. Node ids are stored
+    /// in cells, so cells must be wide enough to count every node the program
+    /// creates. The final state is decoded with [`Program::decode_bf_state`].
+    pub fn to_bf(&self) -> String {
+        let block = Block::from(self);
+        let mut e = Emitter {
+            bf: String::new(),
+            ptr: 0,
+            gutter: LOOP_FLAGS + loop_depth(&block),
+        };
+        e.set(CUR, 1);
+        e.set(NROOTS, 1);
+        e.set(NEXT, 2);
+        e.set(ACTIVE, 1);
+        e.modify(CUR, ROOTS, 1);
+        e.block(&block, 0);
+        e.bf
+    }
+
+    /// Decodes the final tree and success flag from the tape of the Brainfuck
+    /// program compiled by [`Program::to_bf`].
+    pub fn decode_bf_state(&self, tape: &[u64]) -> Result<(RootedTree, bool), BfStateError> {
+        let gutter = LOOP_FLAGS + loop_depth(&Block::from(self));
+        let cell = |i: usize| tape.get(i).copied().unwrap_or(0);
+        let field = |node: u64, field: usize| {
+            usize::try_from(node)
+                .ok()
+                .and_then(|node| node.checked_mul(RECORD))
+                .and_then(|offset| offset.checked_add(gutter + field))
+                .map_or(0, cell)
+        };
+        let max_nodes = (tape.len().saturating_sub(gutter) / RECORD) as u64;
+
+        let mut tree = MultiTree::new();
+        // A new tree has no limit on nodes.
+        let root = tree.new_node().unwrap();
+        let mut ids = vec![(1, root)];
+        let mut stack = vec![(1, root)];
+        while let Some((node, id)) = stack.pop() {
+            if ids.len() as u64 > max_nodes {
+                return Err(BfStateError::InvalidNode(node));
+            }
+            for (child_field, side) in [(LEFT, 1), (RIGHT, 2)] {
+                let child = field(node, child_field);
+                if child == 0 {
+                    continue;
+                }
+                if field(child, PARENT) != node || field(child, SIDE) != side {
+                    return Err(BfStateError::InvalidNode(child));
+                }
+                let child_id = if side == 1 {
+                    tree.new_left(id).unwrap()
+                } else {
+                    tree.new_right(id).unwrap()
+                };
+                ids.push((child, child_id));
+                stack.push((child, child_id));
+            }
+        }
+
+        let cur = cell(CUR);
+        let cursor = ids
+            .iter()
+            .find(|&&(node, _)| node == cur)
+            .map(|&(_, id)| id)
+            .ok_or(BfStateError::InvalidCursor)?;
+        let mut path = Vec::new();
+        let mut node = cur;
+        while node != 0 {
+            path.push(node);
+            node = field(node, PARENT);
+        }
+        let mut root_stack = Vec::new();
+        for &node in path.iter().rev() {
+            let id = ids.iter().find(|&&(n, _)| n == node).unwrap().1;
+            let count = field(node, ROOTS) as usize;
+            root_stack.extend((0..count).map(|_| id));
+        }
+        if root_stack.len() as u64 != cell(NROOTS) || root_stack.first() != Some(&root) {
+            return Err(BfStateError::InvalidRootStack);
+        }
+        let tree = RootedTree::from_parts(tree, root, cursor, root_stack);
+        Ok((tree, cell(SUCCESS) != 0))
+    }
+}
+
+fn loop_depth(block: &Block) -> usize {
+    let nested = block.stmts().iter().map(|stmt| match stmt {
+        Stmt::Loop(body) => 1 + loop_depth(body),
+        _ => 0,
+    });
+    nested.max().unwrap_or(0)
+}
+
+/// Emits Brainfuck, while tracking the position of the pointer. Every
+/// sequence it emits returns the pointer to a statically known cell.
+struct Emitter {
+    bf: String,
+    ptr: usize,
+    gutter: usize,
+}
+
+impl Emitter {
+    fn block(&mut self, block: &Block, depth: usize) {
+        // Once a `?` may have broken out of this loop, skip the rest of it.
+        let mut guarded = false;
+        for stmt in block.stmts() {
+            if guarded {
+                self.copy(ACTIVE, GUARD);
+                self.loop_(GUARD, |e| {
+                    e.clear(GUARD);
+                    e.stmt(stmt, depth);
+                });
+            } else {
+                self.stmt(stmt, depth);
+            }
+            guarded |= *stmt == Stmt::Break;
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, depth: usize) {
+        match stmt {
+            Stmt::MoveLeft => self.move_down(LEFT),
+            Stmt::MoveRight => self.move_down(RIGHT),
+            Stmt::MoveUp => {
+                self.is_not_root(T1);
+                self.clear(SUCCESS);
+                self.loop_(T1, |e| {
+                    e.clear(T1);
+                    e.load(CUR, PARENT, V);
+                    e.clear(CUR);
+                    e.move_add(V, &[CUR]);
+                    e.set(SUCCESS, 1);
+                });
+            }
+            Stmt::PushRoot => {
+                self.modify(CUR, ROOTS, 1);
+                self.add(NROOTS, 1);
+            }
+            Stmt::PopRoot => {
+                self.clear(T1);
+                self.copy(NROOTS, T1);
+                self.add(T1, -1);
+                self.clear(SUCCESS);
+                self.loop_(T1, |e| {
+                    e.clear(T1);
+                    // The top root is the closest root above the cursor.
+                    e.clear(X);
+                    e.copy(CUR, X);
+                    e.is_not_root_at(X, T3);
+                    e.loop_(T3, |e| {
+                        e.load(X, PARENT, W);
+                        e.clear(X);
+                        e.move_add(W, &[X]);
+                        e.is_not_root_at(X, T3);
+                    });
+                    e.modify(X, ROOTS, -1);
+                    e.add(NROOTS, -1);
+                    e.set(SUCCESS, 1);
+                });
+            }
+            Stmt::Loop(body) => {
+                let flag = LOOP_FLAGS + depth;
+                self.set(flag, 1);
+                self.loop_(flag, |e| {
+                    e.clear(flag);
+                    e.block(body, depth + 1);
+                    // Repeat on success, unless a `?` broke out.
+                    e.clear(T1);
+                    e.copy(ACTIVE, T1);
+                    e.set(T2, 1);
+                    e.loop_(T1, |e| {
+                        e.clear(T1);
+                        e.clear(T2);
+                        e.copy(SUCCESS, flag);
+                    });
+                    e.loop_(T2, |e| {
+                        e.clear(T2);
+                        e.set(ACTIVE, 1);
+                    });
+                    e.set(SUCCESS, 1);
+                });
+            }
+            Stmt::NewLeft => self.new_child(LEFT, 1),
+            Stmt::NewRight => self.new_child(RIGHT, 2),
+            Stmt::Delete => {
+                self.is_not_root(T1);
+                self.clear(SUCCESS);
+                self.loop_(T1, |e| {
+                    e.clear(T1);
+                    e.load(CUR, SIDE, V);
+                    e.load(CUR, PARENT, W);
+                    e.add(V, -1);
+                    e.set(T2, 1);
+                    e.loop_(V, |e| {
+                        e.clear(V);
+                        e.clear(T2);
+                        e.clear(T3);
+                        e.store(W, RIGHT, T3);
+                    });
+                    e.loop_(T2, |e| {
+                        e.clear(T2);
+                        e.clear(T3);
+                        e.store(W, LEFT, T3);
+                    });
+                    e.clear(CUR);
+                    e.move_add(W, &[CUR]);
+                    e.set(SUCCESS, 1);
+                });
+            }
+            Stmt::Break => {
+                self.load(CUR, ROOTS, V);
+                self.clear(SUCCESS);
+                self.loop_(V, |e| {
+                    e.clear(V);
+                    e.set(SUCCESS, 1);
+                    e.clear(ACTIVE);
+                });
+            }
+        }
+    }
+
+    fn move_down(&mut self, field: usize) {
+        self.load(CUR, field, V);
+        self.clear(SUCCESS);
+        self.loop_(V, |e| {
+            e.clear(CUR);
+            e.move_add(V, &[CUR]);
+            e.set(SUCCESS, 1);
+        });
+    }
+
+    fn new_child(&mut self, field: usize, side: i64) {
+        self.store(NEXT, PARENT, CUR);
+        self.modify(NEXT, SIDE, side);
+        self.store(CUR, field, NEXT);
+        self.add(NEXT, 1);
+        self.set(SUCCESS, 1);
+    }
+
+    /// Sets `flag` to whether the cursor is not a root.
+    fn is_not_root(&mut self, flag: usize) {
+        self.is_not_root_at(CUR, flag);
+    }
+
+    fn is_not_root_at(&mut self, node: usize, flag: usize) {
+        self.load(node, ROOTS, V);
+        self.set(flag, 1);
+        self.loop_(V, |e| {
+            e.clear(V);
+            e.clear(flag);
+        });
+    }
+
+    /// Sets `dst` to a field of the node in `addr`.
+    fn load(&mut self, addr: usize, field: usize, dst: usize) {
+        self.clear(dst);
+        self.travel(addr);
+        let g = self.gutter;
+        self.move_add(g + field, &[g + PAYLOAD, g + COUNT]);
+        self.move_add(g + COUNT, &[g + field]);
+        self.travel_back();
+        self.move_add(g + PAYLOAD, &[dst]);
+    }
+
+    /// Sets a field of the node in `addr` to `src`.
+    fn store(&mut self, addr: usize, field: usize, src: usize) {
+        let g = self.gutter;
+        self.copy(src, g + PAYLOAD);
+        self.travel(addr);
+        self.clear(g + field);
+        self.move_add(g + PAYLOAD, &[g + field]);
+        self.travel_back();
+    }
+
+    /// Adds to a field of the node in `addr`.
+    fn modify(&mut self, addr: usize, field: usize, n: i64) {
+        self.travel(addr);
+        self.add(self.gutter + field, n);
+        self.travel_back();
+    }
+
+    /// Moves to the record of the node in `addr`, carrying the payload and
+    /// leaving a trail of breadcrumbs. The pointer is then tracked relative
+    /// to that record, as if it were the gutter.
+    fn travel(&mut self, addr: usize) {
+        let g = self.gutter;
+        self.copy(addr, g + COUNT);
+        self.goto(g + COUNT);
+        let (right, left) = (">".repeat(RECORD), "<".repeat(RECORD));
+        self.bf.push_str("[-[-");
+        self.bf.push_str(&right);
+        self.bf.push('+');
+        self.bf.push_str(&left);
+        self.bf.push_str("]>[-");
+        self.bf.push_str(&right);
+        self.bf.push('+');
+        self.bf.push_str(&left);
+        self.bf.push_str("]<");
+        self.bf.push_str(&right);
+        self.bf.push_str("<+>]");
+    }
+
+    /// Follows the breadcrumbs back to the gutter, carrying the payload.
+    fn travel_back(&mut self) {
+        self.goto(self.gutter + CRUMB);
+        let (right, left) = (">".repeat(RECORD), "<".repeat(RECORD));
+        self.bf.push_str("[->>[-");
+        self.bf.push_str(&left);
+        self.bf.push('+');
+        self.bf.push_str(&right);
+        self.bf.push_str("]<<");
+        self.bf.push_str(&left);
+        self.bf.push(']');
+    }
+
+    fn goto(&mut self, cell: usize) {
+        if cell > self.ptr {
+            self.bf.extend((self.ptr..cell).map(|_| '>'));
+        } else {
+            self.bf.extend((cell..self.ptr).map(|_| '<'));
+        }
+        self.ptr = cell;
+    }
+
+    fn add(&mut self, cell: usize, n: i64) {
+        self.goto(cell);
+        let ch = if n < 0 { '-' } else { '+' };
+        self.bf.extend((0..n.unsigned_abs()).map(|_| ch));
+    }
+
+    fn clear(&mut self, cell: usize) {
+        self.goto(cell);
+        self.bf.push_str("[-]");
+    }
+
+    fn set(&mut self, cell: usize, n: i64) {
+        self.clear(cell);
+        self.add(cell, n);
+    }
+
+    /// Loops while `cell` is non-zero. The body must return the pointer to a
+    /// known cell.
+    fn loop_(&mut self, cell: usize, body: impl FnOnce(&mut Self)) {
+        self.goto(cell);
+        self.bf.push('[');
+        body(self);
+        self.goto(cell);
+        self.bf.push(']');
+    }
+
+    /// Adds `src` to each of `dsts` and clears it.
+    fn move_add(&mut self, src: usize, dsts: &[usize]) {
+        self.loop_(src, |e| {
+            e.add(src, -1);
+            for &dst in dsts {
+                e.add(dst, 1);
+            }
+        });
+    }
+
+    /// Adds `src` to `dst`, keeping `src`.
+    fn copy(&mut self, src: usize, dst: usize) {
+        self.move_add(src, &[dst, T0]);
+        self.move_add(T0, &[src]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{corpus, BfMachine, VM};
+
+    fn check(name: &str, prog: &Program) {
+        let mut vm = VM::new(prog.clone());
+        vm.run().unwrap();
+        let mut bf = BfMachine::new(&prog.to_bf(), 32).unwrap();
+        bf.run().unwrap();
+        let (tree, success) = prog.decode_bf_state(bf.tape()).unwrap();
+        assert_eq!(vm.tree().diff(&tree), None, "{name}");
+        assert_eq!(success, vm.success, "{name}");
+    }
+
+    #[test]
+    fn lower_tutorial() {
+        for (name, prog) in corpus::programs() {
+            if name.starts_with("tutorial/") {
+                check(&name, &prog);
+            }
+        }
+    }
+
+    #[test]
+    fn lower_roots_and_breaks() {
+        for src in ["+<{*>-}^", "+<+<(^?)*", "*>{+<(?-)}(^)", "+<^-<"] {
+            check(src, &Program::parse(src).unwrap());
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let prog = Program::parse("+").unwrap();
+        assert_eq!(
+            prog.decode_bf_state(&[]).unwrap_err(),
+            BfStateError::InvalidNode(1),
+        );
+    }
+}
@@ -0,0 +1,11 @@
+mod compile;
+mod error;
+mod interp;
+mod lower;
+mod tape;
+
+pub use compile::*;
+pub use error::*;
+pub use interp::*;
+pub use lower::*;
+pub use tape::*;
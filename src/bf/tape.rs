@@ -0,0 +1,166 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::tree::{NodeId, RootedTree, TreeView};
+
+/// A Brainfuck tape decoded from a tree. It displays as numbers, or with `{:#}`
+/// as characters, with the current cell in brackets.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BfTape {
+    pub cells: Vec<usize>,
+    pub current: usize,
+}
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum BfTapeError {
+    #[error("cell {0} is not a numeral")]
+    InvalidCell(usize),
+    #[error("current cell is not on the tape")]
+    MissingCurrent,
+}
+
+impl TreeView<'_> {
+    /// Decodes a Brainfuck tape, with the first cell at the cursor and
+    /// `current` as the current cell.
+    ///
+    /// Zero cells after the current cell at the end of the tape are dropped,
+    /// as they are indistinguishable from cells never visited. This includes
+    /// the empty cell kept past the end of an unbounded tape.
+    pub fn decode_bf_tape(&self, current: NodeId) -> Result<BfTape, BfTapeError> {
+        let mut view = self.clone();
+        let mut cells = Vec::new();
+        let mut current_index = None;
+        loop {
+            if view.cursor() == current {
+                current_index = Some(cells.len());
+            }
+            let value = match view.left() {
+                Some(left) => view.at(left).count_left_only().map(|n| n + 1),
+                None => Some(0),
+            };
+            cells.push(value.ok_or(BfTapeError::InvalidCell(cells.len()))?);
+            if !view.move_right() {
+                break;
+            }
+        }
+        let current = current_index.ok_or(BfTapeError::MissingCurrent)?;
+        while cells.len() > current + 1 && cells.last() == Some(&0) {
+            cells.pop();
+        }
+        Ok(BfTape { cells, current })
+    }
+}
+
+impl RootedTree {
+    /// Decodes the Brainfuck tape of a program compiled with [`compile_bf`],
+    /// where the current cell is the top root.
+    pub fn decode_bf_tape(&self) -> Result<BfTape, BfTapeError> {
+        let current = *self
+            .root_stack()
+            .last()
+            .ok_or(BfTapeError::MissingCurrent)?;
+        self.unrooted().view(self.root()).decode_bf_tape(current)
+    }
+}
+
+impl fmt::Display for BfTape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, &cell) in self.cells.iter().enumerate() {
+            if i != 0 {
+                write!(f, " ")?;
+            }
+            if i == self.current {
+                write!(f, "[")?;
+            }
+            match u8::try_from(cell) {
+                Ok(byte) if f.alternate() => write!(f, "'{}'", byte.escape_ascii())?,
+                _ => write!(f, "{cell}")?,
+            }
+            if i == self.current {
+                write!(f, "]")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_bf, corpus, BfOptions, Program, Tape, VM};
+
+    fn run(src: &str, opts: &BfOptions) -> BfTape {
+        let mut vm = VM::new(compile_bf(src, opts).unwrap());
+        vm.run().unwrap();
+        vm.tree().decode_bf_tape().unwrap()
+    }
+
+    #[test]
+    fn decode_unbounded() {
+        let opts = BfOptions::default();
+        let tape = run(">>+++<+", &opts);
+        assert_eq!(tape.cells, [0, 1, 3]);
+        assert_eq!(tape.current, 1);
+        // The empty cell past the end is not shown.
+        let tape = run(">>", &opts);
+        assert_eq!(tape.cells, [0, 0, 0]);
+        assert_eq!(tape.current, 2);
+        assert_eq!(tape.to_string(), "0 0 [0]");
+    }
+
+    #[test]
+    fn decode_bounded() {
+        let opts = BfOptions {
+            tape: Tape::Bounded(5),
+            ..BfOptions::default()
+        };
+        let tape = run("+>>+<", &opts);
+        assert_eq!(tape.cells, [1, 0, 1]);
+        assert_eq!(tape.current, 1);
+        let tape = run(">>>>>>++", &opts);
+        assert_eq!(tape.cells, [0, 0, 0, 0, 2]);
+        assert_eq!(tape.current, 4);
+    }
+
+    #[test]
+    fn display() {
+        let tape = BfTape {
+            cells: vec![72, 105, 0, 300],
+            current: 2,
+        };
+        assert_eq!(tape.to_string(), "72 105 [0] 300");
+        assert_eq!(format!("{tape:#}"), "'H' 'i' ['\\x00'] 300");
+    }
+
+    #[test]
+    fn decode_corpus() {
+        let expected = [
+            ("brainfuck/42_nowrap.leaf", "0 [42]"),
+            ("brainfuck/42_wrap.leaf", "0 [42]"),
+            (
+                "brainfuck/beaver_17.leaf",
+                "0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 29 [0] 181 214",
+            ),
+        ];
+        let programs = corpus::programs();
+        for (name, tape) in expected {
+            let (_, prog) = programs.iter().find(|(n, _)| n == name).unwrap();
+            let mut vm = VM::new(prog.clone());
+            vm.run().unwrap();
+            let decoded = vm.tree().decode_bf_tape().unwrap();
+            assert_eq!(decoded.to_string(), tape, "{name}");
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut vm = VM::new(Program::parse("*{+<*").unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.tree().decode_bf_tape(), Err(BfTapeError::InvalidCell(0)));
+        // The top root is a cell value, off the spine.
+        let mut vm = VM::new(Program::parse("*+<{").unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.tree().decode_bf_tape(), Err(BfTapeError::MissingCurrent));
+    }
+}
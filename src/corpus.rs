@@ -0,0 +1,40 @@
+//! The example programs under `programs/`, for tests.
+
+use std::fs;
+use std::path::Path;
+
+use crate::Program;
+
+/// Returns the name and source of every `.leaf` file under `programs/`,
+/// sorted by path.
+pub fn sources() -> Vec<(String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("programs");
+    let mut sources = Vec::new();
+    visit(&dir, &dir, &mut sources);
+    sources.sort();
+    sources
+}
+
+/// Returns the name and parsed program of every `.leaf` file under
+/// `programs/`.
+pub fn programs() -> Vec<(String, Program)> {
+    sources()
+        .into_iter()
+        .map(|(name, src)| {
+            let prog = Program::parse(&src).unwrap_or_else(|err| panic!("{name}: {err}"));
+            (name, prog)
+        })
+        .collect()
+}
+
+fn visit(base: &Path, dir: &Path, sources: &mut Vec<(String, String)>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            visit(base, &path, sources);
+        } else if path.extension().is_some_and(|ext| ext == "leaf") {
+            let name = path.strip_prefix(base).unwrap().display().to_string();
+            sources.push((name, fs::read_to_string(&path).unwrap()));
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+use std::{fmt, io};
+
+use thiserror::Error;
+
+use crate::tree::{MultiTree, NodeId, RootedTree};
+use crate::{Engine, ParseMetaVMError, Program, RunStatus, Semantics, VMError, WrapMetaError, VM};
+
+/// The location of a node, as the moves from the root of its tree to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TreePath(pub String);
+
+/// The first structural difference between two trees, ignoring node ids.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TreeDiff {
+    /// A node exists in only one of the trees.
+    Node { path: TreePath, in_first: bool },
+    /// The cursors are at different nodes.
+    Cursor { first: TreePath, second: TreePath },
+    /// The root stacks differ.
+    RootStack {
+        first: Vec<TreePath>,
+        second: Vec<TreePath>,
+    },
+}
+
+/// A program that runs differently on [`VM`] and through `leaf.leaf`, or that
+/// cannot be compared.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum DiffTestError {
+    /// The program cannot run through `leaf.leaf`, so it is skipped instead
+    /// of run.
+    #[error(transparent)]
+    Unsupported(#[from] WrapMetaError),
+    #[error("native: {0}")]
+    Native(VMError),
+    #[error("native: exceeded step limit")]
+    NativeOutOfFuel,
+    #[error("meta: {0}")]
+    Meta(VMError),
+    #[error("meta: exceeded step limit")]
+    MetaOutOfFuel,
+    #[error("meta: {0}")]
+    Decode(#[from] ParseMetaVMError),
+    #[error("success differs: native {native}, meta {meta}")]
+    Success { native: bool, meta: bool },
+    #[error("native and meta differ: {0}")]
+    Diverged(TreeDiff),
+}
+
+/// A program that runs differently with an [`Engine`] than with the
+/// interpreter.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum EngineDiffError {
+    #[error("compiling for the engine: {0}")]
+    Compile(io::ErrorKind),
+    #[error("results differ: interpreter {interpreter:?}, engine {engine:?}")]
+    Result {
+        interpreter: Result<RunStatus, VMError>,
+        engine: Result<RunStatus, VMError>,
+    },
+    #[error("pc differs: interpreter {interpreter}, engine {engine}")]
+    Pc { interpreter: usize, engine: usize },
+    #[error("success differs: interpreter {interpreter}, engine {engine}")]
+    Success { interpreter: bool, engine: bool },
+    #[error("interpreter and engine differ: {0}")]
+    Diverged(TreeDiff),
+}
+
+/// Runs a program directly on [`VM`] and through the self-interpreter
+/// `leaf.leaf`, each for at most `max_steps` steps, and compares the final
+/// states. Programs using `?` are rejected up front with
+/// [`DiffTestError::Unsupported`].
+pub fn difftest(prog: &Program, max_steps: u64) -> Result<(), DiffTestError> {
+    let wrapped = prog.wrap_meta()?;
+    let mut native = VM::new(prog.clone());
+    match native.run_for(max_steps) {
+        Ok(RunStatus::Finished) | Err(VMError::Terminated) => {}
+        Ok(RunStatus::OutOfFuel) => return Err(DiffTestError::NativeOutOfFuel),
+        Err(err) => return Err(DiffTestError::Native(err)),
+    }
+    let mut meta = VM::new(wrapped);
+    match meta.run_for(max_steps) {
+        Ok(RunStatus::Finished) => {}
+        Ok(RunStatus::OutOfFuel) => return Err(DiffTestError::MetaOutOfFuel),
+        Err(err) => return Err(DiffTestError::Meta(err)),
+    }
+    let meta = meta.tree().parse_meta_vm()?;
+    if native.success != meta.success {
+        return Err(DiffTestError::Success {
+            native: native.success,
+            meta: meta.success,
+        });
+    }
+    match native.tree().diff(meta.tree()) {
+        Some(diff) => Err(DiffTestError::Diverged(diff)),
+        None => Ok(()),
+    }
+}
+
+/// Runs a program with the interpreter and with another engine, each for at
+/// most `max_steps` steps, and compares the results and final states.
+pub fn difftest_engine(
+    prog: &Program,
+    semantics: Semantics,
+    engine: Engine,
+    max_steps: u64,
+) -> Result<(), EngineDiffError> {
+    let mut interpreter = VM::with_semantics(prog.clone(), semantics);
+    let mut other = VM::with_semantics(prog.clone(), semantics);
+    other
+        .set_engine(engine)
+        .map_err(|err| EngineDiffError::Compile(err.kind()))?;
+    let res = (interpreter.run_for(max_steps), other.run_for(max_steps));
+    if res.0 != res.1 {
+        return Err(EngineDiffError::Result {
+            interpreter: res.0,
+            engine: res.1,
+        });
+    }
+    if interpreter.pc != other.pc {
+        return Err(EngineDiffError::Pc {
+            interpreter: interpreter.pc,
+            engine: other.pc,
+        });
+    }
+    if interpreter.success != other.success {
+        return Err(EngineDiffError::Success {
+            interpreter: interpreter.success,
+            engine: other.success,
+        });
+    }
+    match interpreter.tree().diff(other.tree()) {
+        Some(diff) => Err(EngineDiffError::Diverged(diff)),
+        None => Ok(()),
+    }
+}
+
+impl RootedTree {
+    /// Compares two trees structurally, along with their cursors and root
+    /// stacks, and returns the first difference. Nodes are compared in
+    /// breadth-first order.
+    pub fn diff(&self, other: &RootedTree) -> Option<TreeDiff> {
+        let (a, b) = (self.unrooted(), other.unrooted());
+        let mut queue = VecDeque::new();
+        queue.push_back((self.root(), other.root(), TreePath::default()));
+        while let Some((id_a, id_b, path)) = queue.pop_front() {
+            let children = [
+                ('<', a[id_a].left(), b[id_b].left()),
+                ('>', a[id_a].right(), b[id_b].right()),
+            ];
+            for (dir, child_a, child_b) in children {
+                let mut path = path.clone();
+                path.0.push(dir);
+                match (child_a, child_b) {
+                    (Some(child_a), Some(child_b)) => queue.push_back((child_a, child_b, path)),
+                    (None, None) => {}
+                    (child_a, _) => {
+                        return Some(TreeDiff::Node {
+                            path,
+                            in_first: child_a.is_some(),
+                        })
+                    }
+                }
+            }
+        }
+
+        let (cursor_a, cursor_b) = (path_to(a, self.cursor()), path_to(b, other.cursor()));
+        if cursor_a != cursor_b {
+            return Some(TreeDiff::Cursor {
+                first: cursor_a,
+                second: cursor_b,
+            });
+        }
+        let roots_a = self.root_stack().iter().map(|&root| path_to(a, root));
+        let roots_b = other.root_stack().iter().map(|&root| path_to(b, root));
+        if !roots_a.clone().eq(roots_b.clone()) {
+            return Some(TreeDiff::RootStack {
+                first: roots_a.collect(),
+                second: roots_b.collect(),
+            });
+        }
+        None
+    }
+}
+
+/// Returns the path from the root of the tree containing a node to it.
+fn path_to(tree: &MultiTree, mut id: NodeId) -> TreePath {
+    let mut moves = Vec::new();
+    while let Some(parent) = tree[id].parent() {
+        moves.push(if tree[parent].left() == Some(id) {
+            '<'
+        } else {
+            '>'
+        });
+        id = parent;
+    }
+    TreePath(moves.into_iter().rev().collect())
+}
+
+impl fmt::Display for TreePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "the root")
+        } else {
+            write!(f, "`{}`", self.0)
+        }
+    }
+}
+
+impl fmt::Display for TreeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeDiff::Node { path, in_first } => {
+                let which = if *in_first { "first" } else { "second" };
+                write!(f, "node at {path} only in {which} tree")
+            }
+            TreeDiff::Cursor { first, second } => {
+                write!(f, "cursor at {first} and {second}")
+            }
+            TreeDiff::RootStack { first, second } => {
+                write!(f, "root stacks [")?;
+                write_paths(f, first)?;
+                write!(f, "] and [")?;
+                write_paths(f, second)?;
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+fn write_paths(f: &mut fmt::Formatter<'_>, paths: &[TreePath]) -> fmt::Result {
+    for (i, path) in paths.iter().enumerate() {
+        if i != 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{path}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{corpus, Inst};
+
+    fn run(src: &str) -> RootedTree {
+        let mut vm = VM::new(Program::parse(src).unwrap());
+        _ = vm.run();
+        vm.tree
+    }
+
+    fn path(path: &str) -> TreePath {
+        TreePath(path.to_owned())
+    }
+
+    #[test]
+    fn difftest_corpus() {
+        for (name, prog) in corpus::programs() {
+            let res = difftest(&prog, 10_000_000);
+            if prog.insts().contains(&Inst::Break) {
+                assert_eq!(
+                    res,
+                    Err(DiffTestError::Unsupported(WrapMetaError::Unsupported)),
+                    "{name}"
+                );
+            } else {
+                assert_eq!(res, Ok(()), "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn difftest_limits() {
+        let prog = Program::parse("+(<+)").unwrap();
+        assert_eq!(difftest(&prog, 100), Err(DiffTestError::NativeOutOfFuel));
+        let prog = Program::parse("+<*>^").unwrap();
+        assert_eq!(difftest(&prog, 100), Err(DiffTestError::MetaOutOfFuel));
+        assert_eq!(difftest(&prog, 1_000_000), Ok(()));
+        let prog = Program::parse("+<(?)").unwrap();
+        assert_eq!(
+            difftest(&prog, 1_000_000),
+            Err(DiffTestError::Unsupported(WrapMetaError::Unsupported))
+        );
+    }
+
+    #[test]
+    fn diff_equal() {
+        // Node ids differ, since the children are created in another order.
+        let a = run("*+<");
+        let b = run("+*<");
+        assert_ne!(a.cursor(), b.cursor());
+        assert_eq!(a.diff(&b), None);
+        assert_eq!(a.diff(&a), None);
+    }
+
+    #[test]
+    fn diff_node() {
+        let a = run("+<+");
+        let b = run("+<*");
+        let diff = TreeDiff::Node {
+            path: path("<<"),
+            in_first: true,
+        };
+        assert_eq!(a.diff(&b), Some(diff));
+        assert_eq!(
+            b.diff(&a),
+            Some(TreeDiff::Node {
+                path: path("<<"),
+                in_first: false,
+            }),
+        );
+        assert_eq!(
+            a.diff(&b).unwrap().to_string(),
+            "node at `<<` only in first tree"
+        );
+    }
+
+    #[test]
+    fn diff_cursor() {
+        let a = run("+*<");
+        let b = run("+*>");
+        let diff = TreeDiff::Cursor {
+            first: path("<"),
+            second: path(">"),
+        };
+        assert_eq!(a.diff(&b), Some(diff));
+        assert_eq!(a.diff(&b).unwrap().to_string(), "cursor at `<` and `>`");
+    }
+
+    #[test]
+    fn diff_root_stack() {
+        let a = run("+{<{");
+        let b = run("+<{{");
+        let diff = TreeDiff::RootStack {
+            first: vec![path(""), path(""), path("<")],
+            second: vec![path(""), path("<"), path("<")],
+        };
+        assert_eq!(a.diff(&b), Some(diff));
+        assert_eq!(
+            a.diff(&b).unwrap().to_string(),
+            "root stacks [the root, the root, `<`] and [the root, `<`, `<`]",
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::{Code, EmitOptions, Op, Program, Semantics};
+
+/// The tree runtime and `main`, which precede the compiled program.
+const RUNTIME: &str = include_str!("runtime.c");
+
+impl Program {
+    /// Compiles the program to a standalone C99 file, which runs it and prints
+    /// the final tree in DOT format, like `leafy run`. Its runtime allocates
+    /// nodes as [`MultiTree`](crate::tree::MultiTree) does, so node ids match
+    /// too. Errors are printed to stderr and exit with status 1.
+    pub fn to_c(&self, opts: EmitOptions) -> String {
+        let code = opts.compile(self);
+        let mut e = CEmitter {
+            c: RUNTIME.to_owned(),
+            code: &code,
+            semantics: opts.semantics,
+            labels: BTreeSet::new(),
+            success: code.ops().iter().any(|op| matches!(op, Op::LoopTail(_))),
+        };
+        e.run();
+        e.c
+    }
+}
+
+/// Emits `run`, with a statement per op and jumps as `goto`.
+struct CEmitter<'a> {
+    c: String,
+    code: &'a Code,
+    semantics: Semantics,
+    /// The ops that are jumped to, where the length of the code is the end.
+    labels: BTreeSet<usize>,
+    /// Whether the success flag is read, which only `)` does.
+    success: bool,
+}
+
+impl CEmitter<'_> {
+    fn run(&mut self) {
+        let len = self.code.len();
+        // With the loop stack, `)` and `?` jump to the loop on top of it,
+        // which is not known statically, so they go through a `switch`.
+        let dispatch = self.semantics.break_keeps_loop
+            && self
+                .code
+                .ops()
+                .iter()
+                .any(|op| matches!(op, Op::LoopTail(_) | Op::Break(_)));
+        for (pc, &op) in self.code.ops().iter().enumerate() {
+            match op {
+                Op::LoopHead(tail) if dispatch => {
+                    self.labels.insert(pc + 1);
+                    self.labels.insert(tail + 1);
+                }
+                Op::LoopTail(head) if !dispatch => {
+                    self.labels.insert(head + 1);
+                }
+                Op::Break(_) if dispatch => {
+                    self.labels.insert(self.restart());
+                }
+                Op::Break(tail) => {
+                    self.labels.insert(self.break_target(tail));
+                }
+                _ => {}
+            }
+        }
+
+        self.c.push_str("\nstatic void run(struct tree *t) {\n");
+        if self.success {
+            self.line("int success = 0;");
+        }
+        if self.semantics.break_keeps_loop {
+            self.line("struct loops loops = {NULL, 0, 0};");
+        }
+        if dispatch {
+            self.line("size_t target;");
+        }
+        let uses_tree = self
+            .code
+            .ops()
+            .iter()
+            .any(|op| !matches!(op, Op::LoopHead(_) | Op::LoopTail(_)));
+        if !uses_tree {
+            self.line("(void)t;");
+        }
+        for (pc, &op) in self.code.ops().iter().enumerate() {
+            if self.labels.contains(&pc) {
+                writeln!(self.c, "l{pc}:").unwrap();
+            }
+            writeln!(self.c, "    /* {op} */").unwrap();
+            self.op(pc, op);
+        }
+        if dispatch {
+            self.line("goto done;");
+            self.c.push_str("dispatch:\n");
+            self.line("switch (target) {");
+            for &pc in self.labels.range(..len) {
+                writeln!(self.c, "    case {pc}:\n        goto l{pc};").unwrap();
+            }
+            self.line("default:");
+            self.line("    goto done;");
+            self.line("}");
+            self.c.push_str("done:\n");
+        } else if self.labels.contains(&len) {
+            self.c.push_str("done:\n");
+            self.line("return;");
+        }
+        if self.semantics.break_keeps_loop {
+            self.line("free(loops.items);");
+        }
+        self.c.push_str("}\n");
+    }
+
+    fn op(&mut self, pc: usize, op: Op) {
+        let keep_loops = self.semantics.break_keeps_loop;
+        match op {
+            Op::MoveLeft => self.set_success("move_left(t)"),
+            Op::MoveRight => self.set_success("move_right(t)"),
+            Op::MoveUp => self.set_success("move_up(t)"),
+            Op::PushRoot => self.line("push_root(t);"),
+            Op::PopRoot if self.semantics.pop_last_root => self.set_success("pop_last_root(t)"),
+            Op::PopRoot => self.set_success("pop_root(t)"),
+            Op::LoopHead(tail) if keep_loops => {
+                self.line(&format!("loop_push(&loops, {pc}, {tail});"));
+            }
+            Op::LoopHead(_) => {}
+            Op::LoopTail(_) if keep_loops => {
+                self.line("target = loop_tail(&loops, success);");
+                self.line("success = 1;");
+                self.line("if (target) {");
+                self.line("    goto dispatch;");
+                self.line("}");
+            }
+            Op::LoopTail(head) => {
+                self.line("if (success) {");
+                self.line(&format!("    goto {};", self.label(head + 1)));
+                self.line("}");
+                self.line("success = 1;");
+            }
+            Op::NewLeft => {
+                self.line("new_left(t);");
+                self.set_success("1");
+            }
+            Op::NewRight => {
+                self.line("new_right(t);");
+                self.set_success("1");
+            }
+            Op::Delete => self.delete(),
+            Op::Break(_) if keep_loops => {
+                let restart = self.restart();
+                self.break_if();
+                self.line("    target = loops.len");
+                self.line("        ? loops.items[loops.len - 1].tail + 1");
+                self.line(&format!("        : {restart};"));
+                self.line("    goto dispatch;");
+                self.line("}");
+            }
+            Op::Break(tail) => {
+                self.break_if();
+                let label = self.label(self.break_target(tail));
+                self.line(&format!("    goto {label};"));
+                self.line("}");
+            }
+            Op::MoveUpToRoot => {
+                self.line("move_up_to_root(t);");
+                self.set_success("1");
+            }
+            Op::MoveLeftToEnd => {
+                self.line("move_left_to_end(t);");
+                self.set_success("1");
+            }
+            Op::MoveRightToEnd => {
+                self.line("move_right_to_end(t);");
+                self.set_success("1");
+            }
+            Op::RepeatMoveUp(n) => self.set_success(&format!("repeat_move_up(t, {n})")),
+            Op::NewLeftChain(n) => {
+                self.line(&format!("new_left_chain(t, {n});"));
+                self.set_success("1");
+            }
+            Op::NewRightChain(n) => {
+                self.line(&format!("new_right_chain(t, {n});"));
+                self.set_success("1");
+            }
+            Op::DeleteLeft => {
+                self.line("new_left(t);");
+                self.line("move_left(t);");
+                self.delete();
+            }
+            Op::DeleteRight => {
+                self.line("new_right(t);");
+                self.line("move_right(t);");
+                self.delete();
+            }
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.semantics.delete_root {
+            self.set_success("delete_root(t)");
+        } else {
+            self.set_success("delete_cursor(t)");
+        }
+    }
+
+    /// Sets the success flag, or only evaluates the value when the flag is
+    /// unused.
+    fn set_success(&mut self, value: &str) {
+        if self.success {
+            self.line(&format!("success = {value};"));
+        } else if value != "1" {
+            self.line(&format!("{value};"));
+        }
+    }
+
+    /// Opens the block taken by a `?`.
+    fn break_if(&mut self) {
+        if self.success {
+            self.line("success = at_root(t);");
+            self.line("if (success) {");
+        } else {
+            self.line("if (at_root(t)) {");
+        }
+    }
+
+    /// Returns the op after a `?` with a statically resolved target.
+    fn break_target(&self, tail: usize) -> usize {
+        if tail == self.code.len() {
+            self.restart()
+        } else {
+            tail + 1
+        }
+    }
+
+    /// Returns the op after a `?` outside of a loop.
+    fn restart(&self) -> usize {
+        if self.semantics.break_restarts {
+            0
+        } else {
+            self.code.len()
+        }
+    }
+
+    fn label(&self, pc: usize) -> String {
+        if pc == self.code.len() {
+            "done".to_owned()
+        } else {
+            format!("l{pc}")
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.c.push_str("    ");
+        self.c.push_str(line);
+        self.c.push('\n');
+    }
+}
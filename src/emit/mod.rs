@@ -0,0 +1,24 @@
+mod c;
+mod rust;
+mod wasm;
+
+use crate::{Code, Program, Semantics};
+
+/// Options for compiling Leaf ahead of time to another language.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct EmitOptions {
+    pub semantics: Semantics,
+    /// Fuses common idioms into superinstructions first, as with
+    /// [`VM::optimized`](crate::VM::optimized).
+    pub optimize: bool,
+}
+
+impl EmitOptions {
+    fn compile(&self, prog: &Program) -> Code {
+        if self.optimize {
+            Code::optimized(prog)
+        } else {
+            Code::new(prog)
+        }
+    }
+}
@@ -0,0 +1,303 @@
+/* Generated by leafy from a Leaf program. Build with a C99 compiler and run to
+ * print the final tree in DOT format, as `leafy run` does. */
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/* The tree is an arena of nodes, as in `MultiTree`, so that node ids, and thus
+ * the output, match Leafy. Ids start at 1 and 0 is none. Freed nodes are
+ * linked through their parents and their children are freed when reused. */
+
+typedef uint32_t node_id;
+
+struct node {
+    node_id left, right, parent;
+};
+
+struct tree {
+    struct node *nodes;
+    size_t len, cap;
+    node_id free;
+    node_id root, cursor;
+    node_id *roots;
+    size_t roots_len, roots_cap;
+};
+
+struct loop {
+    size_t head, tail;
+};
+
+/* The loops being executed, with `Semantics::break_keeps_loop`. */
+struct loops {
+    struct loop *items;
+    size_t len, cap;
+};
+
+static void fail(const char *msg) {
+    fprintf(stderr, "%s\n", msg);
+    exit(1);
+}
+
+static void *grow(void *items, size_t *cap, size_t size) {
+    *cap = *cap ? *cap * 2 : 4;
+    items = realloc(items, *cap * size);
+    if (!items) {
+        fail("out of memory");
+    }
+    return items;
+}
+
+static struct node *node(struct tree *t, node_id id) {
+    return &t->nodes[id - 1];
+}
+
+static void free_node(struct tree *t, node_id id) {
+    node(t, id)->parent = t->free;
+    t->free = id;
+}
+
+static node_id new_node(struct tree *t) {
+    node_id id = t->free;
+    if (id) {
+        struct node *n = node(t, id);
+        node_id left = n->left, right = n->right;
+        t->free = n->parent;
+        n->left = n->right = n->parent = 0;
+        if (right) {
+            free_node(t, right);
+        }
+        if (left) {
+            free_node(t, left);
+        }
+        return id;
+    }
+    if (t->len >= UINT32_MAX) {
+        fail("out of memory");
+    }
+    if (t->len == t->cap) {
+        t->nodes = grow(t->nodes, &t->cap, sizeof(struct node));
+    }
+    t->nodes[t->len].left = t->nodes[t->len].right = t->nodes[t->len].parent = 0;
+    return (node_id)++t->len;
+}
+
+static void tree_init(struct tree *t) {
+    t->nodes = NULL;
+    t->len = t->cap = 0;
+    t->free = 0;
+    t->root = t->cursor = new_node(t);
+    t->roots = NULL;
+    t->roots_len = t->roots_cap = 0;
+    t->roots = grow(t->roots, &t->roots_cap, sizeof(node_id));
+    t->roots[t->roots_len++] = t->root;
+}
+
+static inline int at_root(struct tree *t) {
+    return t->roots_len && t->roots[t->roots_len - 1] == t->cursor;
+}
+
+/* Returns the parent of the cursor, or 0 at a root. */
+static inline node_id parent(struct tree *t) {
+    return !at_root(t) && t->roots_len ? node(t, t->cursor)->parent : 0;
+}
+
+static inline int move_left(struct tree *t) {
+    node_id left = node(t, t->cursor)->left;
+    if (left) {
+        t->cursor = left;
+    }
+    return left != 0;
+}
+
+static inline int move_right(struct tree *t) {
+    node_id right = node(t, t->cursor)->right;
+    if (right) {
+        t->cursor = right;
+    }
+    return right != 0;
+}
+
+static inline int move_up(struct tree *t) {
+    node_id p = parent(t);
+    if (p) {
+        t->cursor = p;
+    }
+    return p != 0;
+}
+
+static inline void new_left(struct tree *t) {
+    node_id id;
+    if (node(t, t->cursor)->left) {
+        free_node(t, node(t, t->cursor)->left);
+    }
+    id = new_node(t);
+    node(t, id)->parent = t->cursor;
+    node(t, t->cursor)->left = id;
+}
+
+static inline void new_right(struct tree *t) {
+    node_id id;
+    if (node(t, t->cursor)->right) {
+        free_node(t, node(t, t->cursor)->right);
+    }
+    id = new_node(t);
+    node(t, id)->parent = t->cursor;
+    node(t, t->cursor)->right = id;
+}
+
+/* Unlinks the cursor from its parent and moves to it. */
+static void detach(struct tree *t, node_id p) {
+    struct node *n = node(t, t->cursor);
+    if (node(t, p)->left == t->cursor) {
+        node(t, p)->left = 0;
+    } else if (node(t, p)->right == t->cursor) {
+        node(t, p)->right = 0;
+    }
+    n->parent = 0;
+    t->cursor = p;
+}
+
+static inline int delete_cursor(struct tree *t) {
+    node_id id = t->cursor, p = parent(t);
+    if (!p) {
+        return 0;
+    }
+    detach(t, p);
+    free_node(t, id);
+    return 1;
+}
+
+/* `-` with `Semantics::delete_root`. */
+static inline int delete_root(struct tree *t) {
+    node_id p;
+    if (!at_root(t)) {
+        return delete_cursor(t);
+    }
+    p = node(t, t->cursor)->parent;
+    if (!p) {
+        fail("deleted the root of the tree");
+    }
+    detach(t, p);
+    return 1;
+}
+
+static inline void push_root(struct tree *t) {
+    if (t->roots_len == t->roots_cap) {
+        t->roots = grow(t->roots, &t->roots_cap, sizeof(node_id));
+    }
+    t->roots[t->roots_len++] = t->cursor;
+}
+
+static inline int pop_root(struct tree *t) {
+    if (t->roots_len <= 1) {
+        return 0;
+    }
+    t->roots_len--;
+    return 1;
+}
+
+/* `}` with `Semantics::pop_last_root`. */
+static inline int pop_last_root(struct tree *t) {
+    if (!t->roots_len) {
+        return 0;
+    }
+    t->roots_len--;
+    return 1;
+}
+
+/* Superinstructions from `Program::optimize`. Those with a count are only
+ * called with a non-zero count. */
+
+static inline void move_up_to_root(struct tree *t) {
+    while (move_up(t)) {
+    }
+}
+
+static inline void move_left_to_end(struct tree *t) {
+    while (move_left(t)) {
+    }
+}
+
+static inline void move_right_to_end(struct tree *t) {
+    while (move_right(t)) {
+    }
+}
+
+static inline int repeat_move_up(struct tree *t, size_t n) {
+    int success = 1;
+    while (n-- && (success = move_up(t))) {
+    }
+    return success;
+}
+
+static inline void new_left_chain(struct tree *t, size_t n) {
+    while (n--) {
+        new_left(t);
+        move_left(t);
+    }
+}
+
+static inline void new_right_chain(struct tree *t, size_t n) {
+    while (n--) {
+        new_right(t);
+        move_right(t);
+    }
+}
+
+static inline void loop_push(struct loops *l, size_t head, size_t tail) {
+    if (l->len == l->cap) {
+        l->items = grow(l->items, &l->cap, sizeof(struct loop));
+    }
+    l->items[l->len].head = head;
+    l->items[l->len].tail = tail;
+    l->len++;
+}
+
+/* Returns the instruction after the head of the top loop, or 0 when it is
+ * exited. */
+static inline size_t loop_tail(struct loops *l, int success) {
+    if (!l->len) {
+        fail("loop stack underflow");
+    }
+    if (success) {
+        return l->items[l->len - 1].head + 1;
+    }
+    l->len--;
+    return 0;
+}
+
+static void dump_dot(struct tree *t) {
+    node_id *queue = malloc(t->len * sizeof(node_id));
+    size_t head = 0, tail = 0;
+    if (!queue) {
+        fail("out of memory");
+    }
+    printf("digraph tree%u {\n", (unsigned)t->root);
+    queue[tail++] = t->root;
+    while (head < tail) {
+        node_id id = queue[head++];
+        struct node *n = node(t, id);
+        printf("    %u [shape=point];\n", (unsigned)id);
+        if (n->left) {
+            printf("    %u -> %u;\n", (unsigned)id, (unsigned)n->left);
+            queue[tail++] = n->left;
+        }
+        if (n->right) {
+            printf("    %u -> %u [style=dashed];\n", (unsigned)id, (unsigned)n->right);
+            queue[tail++] = n->right;
+        }
+    }
+    printf("}\n");
+    free(queue);
+}
+
+static void run(struct tree *t);
+
+int main(void) {
+    struct tree t;
+    tree_init(&t);
+    run(&t);
+    dump_dot(&t);
+    return 0;
+}
@@ -0,0 +1,685 @@
+;; Generated by leafy from a Leaf program. `run` runs it and returns 0 when it
+;; finishes, 1 when it deletes the root of the tree, 2 when the loop stack
+;; underflows, or 3 when memory is exhausted, which stops it at the failing
+;; instruction. Afterwards, `tree` returns the address of the nodes and their
+;; number.
+;;
+;; Nodes are laid out as `Node` in Leafy, in 12 bytes with the ids of the left
+;; child, right child, and parent, where ids start at 1 and 0 is none. The
+;; root of the tree is node 1. As in `MultiTree`, freed nodes are linked
+;; through their parents and their children are freed when reused, so that
+;; node ids match Leafy.
+(module
+  (memory (export "memory") 1)
+
+  ;; The arrays of nodes, roots, and loops are allocated by bumping the heap
+  ;; and are moved when they are full.
+  (global $heap (mut i32) (i32.const 0))
+
+  (global $nodes (mut i32) (i32.const 0))
+  (global $nodes_len (mut i32) (i32.const 0))
+  (global $nodes_cap (mut i32) (i32.const 0))
+  (global $free (mut i32) (i32.const 0))
+  (global $cursor (mut i32) (i32.const 0))
+
+  (global $roots (mut i32) (i32.const 0))
+  (global $roots_len (mut i32) (i32.const 0))
+  (global $roots_cap (mut i32) (i32.const 0))
+
+  ;; The loops being executed, with `Semantics::break_keeps_loop`, as pairs
+  ;; of the targets of their `)` and `?`.
+  (global $loops (mut i32) (i32.const 0))
+  (global $loops_len (mut i32) (i32.const 0))
+  (global $loops_cap (mut i32) (i32.const 0))
+
+  (func (export "tree") (result i32 i32)
+    global.get $nodes
+    global.get $nodes_len)
+
+  ;; The first node and root fit in the initial page, so only the result of
+  ;; `push_root` is checked.
+  (func $init (result i32)
+    i32.const 0
+    global.set $heap
+    i32.const 0
+    global.set $nodes_len
+    i32.const 0
+    global.set $nodes_cap
+    i32.const 0
+    global.set $free
+    i32.const 0
+    global.set $roots_len
+    i32.const 0
+    global.set $roots_cap
+    i32.const 0
+    global.set $loops_len
+    i32.const 0
+    global.set $loops_cap
+    call $new_node
+    global.set $cursor
+    global.get $cursor
+    call $push_root)
+
+  ;; Allocates bytes from the heap, growing memory as needed, or returns -1
+  ;; when memory is exhausted.
+  (func $alloc (param $size i64) (result i32)
+    (local $ptr i32)
+    (local $end i64)
+    (local $pages i64)
+    global.get $heap
+    local.tee $ptr
+    i64.extend_i32_u
+    local.get $size
+    i64.add
+    local.tee $end
+    i64.const 0x1_0000_0000
+    i64.gt_u
+    if
+      i32.const -1
+      return
+    end
+    local.get $end
+    i64.const 0xffff
+    i64.add
+    i64.const 16
+    i64.shr_u
+    memory.size
+    i64.extend_i32_u
+    i64.sub
+    local.tee $pages
+    i64.const 0
+    i64.gt_s
+    if
+      local.get $pages
+      i32.wrap_i64
+      memory.grow
+      i32.const -1
+      i32.eq
+      if
+        i32.const -1
+        return
+      end
+    end
+    local.get $end
+    i32.wrap_i64
+    global.set $heap
+    local.get $ptr)
+
+  ;; Moves an array of `len` elements to a new allocation of `cap` elements,
+  ;; or returns -1 when memory is exhausted.
+  (func $realloc (param $ptr i32) (param $len i32) (param $cap i32) (param $size i32) (result i32)
+    (local $new i32)
+    local.get $cap
+    i64.extend_i32_u
+    local.get $size
+    i64.extend_i32_u
+    i64.mul
+    call $alloc
+    local.tee $new
+    i32.const -1
+    i32.eq
+    if
+      i32.const -1
+      return
+    end
+    local.get $new
+    local.get $ptr
+    local.get $len
+    local.get $size
+    i32.mul
+    memory.copy
+    local.get $new)
+
+  ;; Returns the doubled capacity of a full array.
+  (func $grow_cap (param $cap i32) (result i32)
+    local.get $cap
+    if (result i32)
+      local.get $cap
+      i32.const 1
+      i32.shl
+    else
+      i32.const 4
+    end)
+
+  (func $node (param $id i32) (result i32)
+    local.get $id
+    i32.const 1
+    i32.sub
+    i32.const 12
+    i32.mul
+    global.get $nodes
+    i32.add)
+
+  (func $left (param $id i32) (result i32)
+    local.get $id
+    call $node
+    i32.load)
+
+  (func $right (param $id i32) (result i32)
+    local.get $id
+    call $node
+    i32.load offset=4)
+
+  (func $parent_of (param $id i32) (result i32)
+    local.get $id
+    call $node
+    i32.load offset=8)
+
+  (func $set_left (param $id i32) (param $left i32)
+    local.get $id
+    call $node
+    local.get $left
+    i32.store)
+
+  (func $set_right (param $id i32) (param $right i32)
+    local.get $id
+    call $node
+    local.get $right
+    i32.store offset=4)
+
+  (func $set_parent (param $id i32) (param $parent i32)
+    local.get $id
+    call $node
+    local.get $parent
+    i32.store offset=8)
+
+  (func $free_node (param $id i32)
+    local.get $id
+    global.get $free
+    call $set_parent
+    local.get $id
+    global.set $free)
+
+  ;; Returns the id of a new node, or 0 when memory is exhausted.
+  (func $new_node (result i32)
+    (local $id i32)
+    (local $left i32)
+    (local $right i32)
+    (local $cap i32)
+    (local $ptr i32)
+    global.get $free
+    local.tee $id
+    if
+      local.get $id
+      call $left
+      local.set $left
+      local.get $id
+      call $right
+      local.set $right
+      local.get $id
+      call $parent_of
+      global.set $free
+      local.get $id
+      i32.const 0
+      call $set_left
+      local.get $id
+      i32.const 0
+      call $set_right
+      local.get $id
+      i32.const 0
+      call $set_parent
+      local.get $right
+      if
+        local.get $right
+        call $free_node
+      end
+      local.get $left
+      if
+        local.get $left
+        call $free_node
+      end
+      local.get $id
+      return
+    end
+    global.get $nodes_len
+    global.get $nodes_cap
+    i32.eq
+    if
+      global.get $nodes
+      global.get $nodes_len
+      global.get $nodes_cap
+      call $grow_cap
+      local.tee $cap
+      i32.const 12
+      call $realloc
+      local.tee $ptr
+      i32.const -1
+      i32.eq
+      if
+        i32.const 0
+        return
+      end
+      local.get $ptr
+      global.set $nodes
+      local.get $cap
+      global.set $nodes_cap
+    end
+    global.get $nodes_len
+    i32.const 1
+    i32.add
+    local.tee $id
+    global.set $nodes_len
+    local.get $id
+    i32.const 0
+    call $set_left
+    local.get $id
+    i32.const 0
+    call $set_right
+    local.get $id
+    i32.const 0
+    call $set_parent
+    local.get $id)
+
+  (func $at_root (result i32)
+    global.get $roots_len
+    if (result i32)
+      global.get $roots
+      global.get $roots_len
+      i32.const 1
+      i32.sub
+      i32.const 4
+      i32.mul
+      i32.add
+      i32.load
+      global.get $cursor
+      i32.eq
+    else
+      i32.const 0
+    end)
+
+  ;; Returns the parent of the cursor, or 0 at a root.
+  (func $parent (result i32)
+    call $at_root
+    global.get $roots_len
+    i32.eqz
+    i32.or
+    if (result i32)
+      i32.const 0
+    else
+      global.get $cursor
+      call $parent_of
+    end)
+
+  (func $move_left (result i32)
+    (local $left i32)
+    global.get $cursor
+    call $left
+    local.tee $left
+    if
+      local.get $left
+      global.set $cursor
+    end
+    local.get $left
+    i32.const 0
+    i32.ne)
+
+  (func $move_right (result i32)
+    (local $right i32)
+    global.get $cursor
+    call $right
+    local.tee $right
+    if
+      local.get $right
+      global.set $cursor
+    end
+    local.get $right
+    i32.const 0
+    i32.ne)
+
+  (func $move_up (result i32)
+    (local $parent i32)
+    call $parent
+    local.tee $parent
+    if
+      local.get $parent
+      global.set $cursor
+    end
+    local.get $parent
+    i32.const 0
+    i32.ne)
+
+  ;; Returns 1, or 0 when memory is exhausted, which leaves the tree as it
+  ;; was, because the old child is reused when there is one.
+  (func $new_left (result i32)
+    (local $id i32)
+    global.get $cursor
+    call $left
+    local.tee $id
+    if
+      local.get $id
+      call $free_node
+    end
+    call $new_node
+    local.tee $id
+    i32.eqz
+    if
+      i32.const 0
+      return
+    end
+    local.get $id
+    global.get $cursor
+    call $set_parent
+    global.get $cursor
+    local.get $id
+    call $set_left
+    i32.const 1)
+
+  ;; Returns 1, or 0 when memory is exhausted, which leaves the tree as it
+  ;; was, because the old child is reused when there is one.
+  (func $new_right (result i32)
+    (local $id i32)
+    global.get $cursor
+    call $right
+    local.tee $id
+    if
+      local.get $id
+      call $free_node
+    end
+    call $new_node
+    local.tee $id
+    i32.eqz
+    if
+      i32.const 0
+      return
+    end
+    local.get $id
+    global.get $cursor
+    call $set_parent
+    global.get $cursor
+    local.get $id
+    call $set_right
+    i32.const 1)
+
+  ;; Unlinks the cursor from its parent and moves to it.
+  (func $detach (param $parent i32)
+    local.get $parent
+    call $left
+    global.get $cursor
+    i32.eq
+    if
+      local.get $parent
+      i32.const 0
+      call $set_left
+    else
+      local.get $parent
+      call $right
+      global.get $cursor
+      i32.eq
+      if
+        local.get $parent
+        i32.const 0
+        call $set_right
+      end
+    end
+    global.get $cursor
+    i32.const 0
+    call $set_parent
+    local.get $parent
+    global.set $cursor)
+
+  (func $delete (result i32)
+    (local $id i32)
+    (local $parent i32)
+    call $parent
+    local.tee $parent
+    i32.eqz
+    if
+      i32.const 0
+      return
+    end
+    global.get $cursor
+    local.set $id
+    local.get $parent
+    call $detach
+    local.get $id
+    call $free_node
+    i32.const 1)
+
+  ;; `-` with `Semantics::delete_root`, which returns -1 at the root of the
+  ;; tree.
+  (func $delete_root (result i32)
+    (local $parent i32)
+    call $at_root
+    i32.eqz
+    if
+      call $delete
+      return
+    end
+    global.get $cursor
+    call $parent_of
+    local.tee $parent
+    i32.eqz
+    if
+      i32.const -1
+      return
+    end
+    local.get $parent
+    call $detach
+    i32.const 1)
+
+  ;; Returns 1, or 0 when memory is exhausted.
+  (func $push_root (param $id i32) (result i32)
+    (local $cap i32)
+    (local $ptr i32)
+    global.get $roots_len
+    global.get $roots_cap
+    i32.eq
+    if
+      global.get $roots
+      global.get $roots_len
+      global.get $roots_cap
+      call $grow_cap
+      local.tee $cap
+      i32.const 4
+      call $realloc
+      local.tee $ptr
+      i32.const -1
+      i32.eq
+      if
+        i32.const 0
+        return
+      end
+      local.get $ptr
+      global.set $roots
+      local.get $cap
+      global.set $roots_cap
+    end
+    global.get $roots
+    global.get $roots_len
+    i32.const 4
+    i32.mul
+    i32.add
+    local.get $id
+    i32.store
+    global.get $roots_len
+    i32.const 1
+    i32.add
+    global.set $roots_len
+    i32.const 1)
+
+  (func $pop_root (result i32)
+    global.get $roots_len
+    i32.const 1
+    i32.le_u
+    if
+      i32.const 0
+      return
+    end
+    global.get $roots_len
+    i32.const 1
+    i32.sub
+    global.set $roots_len
+    i32.const 1)
+
+  ;; `}` with `Semantics::pop_last_root`.
+  (func $pop_last_root (result i32)
+    global.get $roots_len
+    i32.eqz
+    if
+      i32.const 0
+      return
+    end
+    global.get $roots_len
+    i32.const 1
+    i32.sub
+    global.set $roots_len
+    i32.const 1)
+
+  ;; Superinstructions from `EmitOptions::optimize`. Those with a count are
+  ;; called with at least 2. The chains return 1, or 0 when memory is
+  ;; exhausted partway through.
+
+  (func $move_up_to_root
+    loop $repeat
+      call $move_up
+      br_if $repeat
+    end)
+
+  (func $move_left_to_end
+    loop $repeat
+      call $move_left
+      br_if $repeat
+    end)
+
+  (func $move_right_to_end
+    loop $repeat
+      call $move_right
+      br_if $repeat
+    end)
+
+  (func $repeat_move_up (param $n i32) (result i32)
+    (local $success i32)
+    loop $repeat
+      call $move_up
+      local.tee $success
+      if
+        local.get $n
+        i32.const 1
+        i32.sub
+        local.tee $n
+        br_if $repeat
+      end
+    end
+    local.get $success)
+
+  (func $new_left_chain (param $n i32) (result i32)
+    loop $repeat
+      call $new_left
+      i32.eqz
+      if
+        i32.const 0
+        return
+      end
+      call $move_left
+      drop
+      local.get $n
+      i32.const 1
+      i32.sub
+      local.tee $n
+      br_if $repeat
+    end
+    i32.const 1)
+
+  (func $new_right_chain (param $n i32) (result i32)
+    loop $repeat
+      call $new_right
+      i32.eqz
+      if
+        i32.const 0
+        return
+      end
+      call $move_right
+      drop
+      local.get $n
+      i32.const 1
+      i32.sub
+      local.tee $n
+      br_if $repeat
+    end
+    i32.const 1)
+
+  ;; Returns 1, or 0 when memory is exhausted.
+  (func $loop_push (param $head i32) (param $tail i32) (result i32)
+    (local $entry i32)
+    (local $cap i32)
+    (local $ptr i32)
+    global.get $loops_len
+    global.get $loops_cap
+    i32.eq
+    if
+      global.get $loops
+      global.get $loops_len
+      global.get $loops_cap
+      call $grow_cap
+      local.tee $cap
+      i32.const 8
+      call $realloc
+      local.tee $ptr
+      i32.const -1
+      i32.eq
+      if
+        i32.const 0
+        return
+      end
+      local.get $ptr
+      global.set $loops
+      local.get $cap
+      global.set $loops_cap
+    end
+    global.get $loops
+    global.get $loops_len
+    i32.const 8
+    i32.mul
+    i32.add
+    local.tee $entry
+    local.get $head
+    i32.store
+    local.get $entry
+    local.get $tail
+    i32.store offset=4
+    global.get $loops_len
+    i32.const 1
+    i32.add
+    global.set $loops_len
+    i32.const 1)
+
+  (func $loop_top (result i32)
+    global.get $loops
+    global.get $loops_len
+    i32.const 1
+    i32.sub
+    i32.const 8
+    i32.mul
+    i32.add)
+
+  ;; Returns the target of the `)` of the top loop, or 0 when it is exited,
+  ;; or -1 when the loop stack is empty.
+  (func $loop_tail (param $success i32) (result i32)
+    global.get $loops_len
+    i32.eqz
+    if
+      i32.const -1
+      return
+    end
+    local.get $success
+    if
+      call $loop_top
+      i32.load
+      return
+    end
+    global.get $loops_len
+    i32.const 1
+    i32.sub
+    global.set $loops_len
+    i32.const 0)
+
+  ;; Returns the target of a `?` in the top loop, or the given target outside
+  ;; of a loop.
+  (func $break_target (param $outside i32) (result i32)
+    global.get $loops_len
+    if (result i32)
+      call $loop_top
+      i32.load offset=4
+    else
+      local.get $outside
+    end)
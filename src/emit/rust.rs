@@ -0,0 +1,138 @@
+use std::fmt::Write;
+
+use crate::{Block, Program, Stmt};
+
+impl Program {
+    /// Compiles the program to a Rust function with Leafy semantics, which
+    /// runs it on a [`RootedTree`](crate::tree::RootedTree):
+    ///
+    /// ```text
+    /// fn name(tree: &mut RootedTree) -> Result<(), OutOfMemory>
+    /// ```
+    ///
+    /// Loops become `loop` blocks and `?` becomes a labelled `break` out of
+    /// its loop, or a `return` outside of one. Types are referred to by their
+    /// absolute paths in the `leafy` crate and the function has no
+    /// visibility, so it can be used by the `leaf!` macro in `leafy-macros`.
+    pub fn to_rust(&self, name: &str) -> String {
+        let block = Block::from(self);
+        let mut e = RustEmitter {
+            rs: String::new(),
+            indent: 1,
+            loops: 0,
+            success: has_loop(&block),
+        };
+        writeln!(
+            e.rs,
+            "fn {name}(tree: &mut ::leafy::tree::RootedTree) \
+             -> ::core::result::Result<(), ::leafy::tree::OutOfMemory> {{"
+        )
+        .unwrap();
+        // The success flag is overwritten between reads.
+        e.line("#![allow(unused_assignments, clippy::all)]");
+        if e.success {
+            e.line("let mut success = false;");
+        }
+        e.block(&block, None);
+        e.line("::core::result::Result::Ok(())");
+        e.rs.push_str("}\n");
+        e.rs
+    }
+}
+
+fn has_loop(block: &Block) -> bool {
+    block
+        .stmts()
+        .iter()
+        .any(|stmt| matches!(stmt, Stmt::Loop(_)))
+}
+
+/// Emits Rust statements, with the label of the enclosing loop in scope.
+struct RustEmitter {
+    rs: String,
+    indent: usize,
+    /// The number of labelled loops so far.
+    loops: usize,
+    /// Whether the success flag is read, which only loops do.
+    success: bool,
+}
+
+impl RustEmitter {
+    fn block(&mut self, block: &Block, label: Option<&str>) {
+        for stmt in block.stmts() {
+            self.stmt(stmt, label);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, label: Option<&str>) {
+        match stmt {
+            Stmt::MoveLeft => self.set_success("tree.move_left()"),
+            Stmt::MoveRight => self.set_success("tree.move_right()"),
+            Stmt::MoveUp => self.set_success("tree.move_up()"),
+            Stmt::PushRoot => self.line("tree.push_root();"),
+            Stmt::PopRoot if self.success => self.line("success = tree.pop_root().is_some();"),
+            Stmt::PopRoot => self.line("tree.pop_root();"),
+            Stmt::Loop(body) => {
+                // Only loops broken by `?` are labelled, so that every label
+                // is used.
+                let label = if body.stmts().contains(&Stmt::Break) {
+                    self.loops += 1;
+                    let label = format!("'l{}", self.loops);
+                    self.line(&format!("{label}: loop {{"));
+                    Some(label)
+                } else {
+                    self.line("loop {");
+                    None
+                };
+                self.indent += 1;
+                self.block(body, label.as_deref());
+                self.line("if !success {");
+                self.line("    break;");
+                self.line("}");
+                self.indent -= 1;
+                self.line("}");
+                self.line("success = true;");
+            }
+            Stmt::NewLeft => {
+                self.line("tree.new_left()?;");
+                self.set_success("true");
+            }
+            Stmt::NewRight => {
+                self.line("tree.new_right()?;");
+                self.set_success("true");
+            }
+            Stmt::Delete => self.set_success("tree.delete()"),
+            Stmt::Break => {
+                if self.success {
+                    self.line("success = tree.at_root();");
+                    self.line("if success {");
+                } else {
+                    self.line("if tree.at_root() {");
+                }
+                match label {
+                    Some(label) => self.line(&format!("    break {label};")),
+                    None => self.line("    return ::core::result::Result::Ok(());"),
+                }
+                self.line("}");
+            }
+        }
+    }
+
+    /// Sets the success flag, or only evaluates the value when the flag is
+    /// unused.
+    fn set_success(&mut self, value: &str) {
+        if self.success {
+            self.line(&format!("success = {value};"));
+        } else if value != "true" {
+            self.line(&format!("{value};"));
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.rs.push_str("    ");
+        }
+        self.rs.push_str(line);
+        self.rs.push('\n');
+    }
+}
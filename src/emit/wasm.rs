@@ -0,0 +1,413 @@
+use std::collections::BTreeSet;
+
+use crate::{Code, EmitOptions, Op, Program, Semantics};
+
+/// The tree runtime, which precedes the compiled program in the module.
+const RUNTIME: &str = include_str!("runtime.wat");
+
+impl Program {
+    /// Compiles the program to a WebAssembly text module, which exports
+    /// `run`, `tree`, and `memory`. `run` runs the program and returns 0 when
+    /// it finishes, 1 for [`VMError::DeletedTreeRoot`](crate::VMError), 2 for
+    /// [`VMError::LoopStackUnderflow`](crate::VMError), or 3 when memory
+    /// cannot grow, like [`VMError::OutOfMemory`](crate::VMError). Afterwards,
+    /// `tree` returns the address of the nodes in `memory` and their number,
+    /// with the root at id 1. Nodes have the layout of
+    /// [`Node`](crate::tree::Node) and are allocated as
+    /// [`MultiTree`](crate::tree::MultiTree) does, so node ids match.
+    pub fn to_wat(&self, opts: EmitOptions) -> String {
+        let code = opts.compile(self);
+        let mut e = WatEmitter {
+            wat: RUNTIME.to_owned(),
+            code: &code,
+            semantics: opts.semantics,
+            labels: BTreeSet::new(),
+            indent: 2,
+        };
+        e.run();
+        e.wat.push_str(")\n");
+        e.wat
+    }
+}
+
+/// Emits `run`. Loops are structured as a `loop` in a `block`, except with
+/// [`Semantics::break_keeps_loop`], where the targets of `)` and `?` are not
+/// known statically. Then, the program is split into segments at each
+/// target, which are jumped to through a `br_table`.
+struct WatEmitter<'a> {
+    wat: String,
+    code: &'a Code,
+    semantics: Semantics,
+    /// The starts of the segments, with dispatch.
+    labels: BTreeSet<usize>,
+    indent: usize,
+}
+
+impl WatEmitter<'_> {
+    fn run(&mut self) {
+        let len = self.code.len();
+        let dispatch = self.semantics.break_keeps_loop && len != 0;
+        self.wat
+            .push_str("\n  (func (export \"run\") (result i32)\n    (local $success i32)\n");
+        if dispatch {
+            self.wat.push_str("    (local $target i32)\n");
+        }
+        self.alloc("call $init");
+
+        if dispatch {
+            self.labels.insert(0);
+            for (pc, &op) in self.code.ops().iter().enumerate() {
+                if let Op::LoopHead(tail) = op {
+                    self.labels.insert(pc + 1);
+                    self.labels.insert(tail + 1);
+                }
+            }
+            self.labels.remove(&len);
+            self.line("i32.const 0");
+            self.line("local.set $target");
+            self.line("loop $dispatch");
+            self.line("block $done");
+            let labels = self.labels.iter().copied().collect::<Vec<_>>();
+            for &pc in labels.iter().rev() {
+                self.line(&format!("block $s{pc}"));
+            }
+            self.line("local.get $target");
+            let table = labels
+                .iter()
+                .map(|pc| format!("$s{pc} "))
+                .collect::<String>();
+            self.line(&format!("br_table {table}$done"));
+            for (pc, &op) in self.code.ops().iter().enumerate() {
+                if self.labels.contains(&pc) {
+                    self.line("end");
+                }
+                self.op(pc, op);
+            }
+            self.line("end");
+            self.line("end");
+        } else {
+            // `?` outside of a loop exits or restarts the program.
+            let top_break = self.code.ops().contains(&Op::Break(len));
+            if top_break {
+                if self.semantics.break_restarts {
+                    self.line("loop $restart");
+                } else {
+                    self.line("block $done");
+                }
+                self.indent += 1;
+            }
+            for (pc, &op) in self.code.ops().iter().enumerate() {
+                self.op(pc, op);
+            }
+            if top_break {
+                self.indent -= 1;
+                self.line("end");
+            }
+        }
+        self.line("i32.const 0)");
+    }
+
+    fn op(&mut self, pc: usize, op: Op) {
+        self.line(&format!(";; {op}"));
+        let dispatch = self.semantics.break_keeps_loop;
+        match op {
+            Op::MoveLeft => self.set_success("call $move_left"),
+            Op::MoveRight => self.set_success("call $move_right"),
+            Op::MoveUp => self.set_success("call $move_up"),
+            Op::PushRoot => {
+                self.line("global.get $cursor");
+                self.alloc("call $push_root");
+            }
+            Op::PopRoot if self.semantics.pop_last_root => {
+                self.set_success("call $pop_last_root");
+            }
+            Op::PopRoot => self.set_success("call $pop_root"),
+            Op::LoopHead(tail) if dispatch => {
+                let head = self.target(pc + 1);
+                let tail = self.target(tail + 1);
+                self.line(&format!("i32.const {head}"));
+                self.line(&format!("i32.const {tail}"));
+                self.alloc("call $loop_push");
+            }
+            Op::LoopHead(_) => {
+                self.line(&format!("block $b{pc}"));
+                self.line(&format!("  loop $l{pc}"));
+                self.indent += 2;
+            }
+            Op::LoopTail(_) if dispatch => {
+                self.line("local.get $success");
+                self.line("call $loop_tail");
+                self.line("local.tee $target");
+                self.line("i32.const 0");
+                self.line("i32.lt_s");
+                self.line("if");
+                self.line("  i32.const 2");
+                self.line("  return");
+                self.line("end");
+                self.set_success("i32.const 1");
+                self.line("local.get $target");
+                self.line("br_if $dispatch");
+            }
+            Op::LoopTail(head) => {
+                self.line("local.get $success");
+                self.line(&format!("br_if $l{head}"));
+                self.indent -= 2;
+                self.line("  end");
+                self.line("end");
+                self.set_success("i32.const 1");
+            }
+            Op::NewLeft => {
+                self.alloc("call $new_left");
+                self.set_success("i32.const 1");
+            }
+            Op::NewRight => {
+                self.alloc("call $new_right");
+                self.set_success("i32.const 1");
+            }
+            Op::Delete => self.delete(),
+            Op::Break(_) if dispatch => {
+                let outside = if self.semantics.break_restarts {
+                    self.target(0)
+                } else {
+                    self.target(self.code.len())
+                };
+                self.line("call $at_root");
+                self.line("local.tee $success");
+                self.line("if");
+                self.line(&format!("  i32.const {outside}"));
+                self.line("  call $break_target");
+                self.line("  local.set $target");
+                self.line("  br $dispatch");
+                self.line("end");
+            }
+            Op::Break(tail) => {
+                self.line("call $at_root");
+                self.line("local.tee $success");
+                if tail != self.code.len() {
+                    let Op::LoopTail(head) = self.code.ops()[tail] else {
+                        unreachable!("`?` targets a `)`");
+                    };
+                    self.line(&format!("br_if $b{head}"));
+                } else if self.semantics.break_restarts {
+                    self.line("br_if $restart");
+                } else {
+                    self.line("br_if $done");
+                }
+            }
+            Op::MoveUpToRoot => {
+                self.line("call $move_up_to_root");
+                self.set_success("i32.const 1");
+            }
+            Op::MoveLeftToEnd => {
+                self.line("call $move_left_to_end");
+                self.set_success("i32.const 1");
+            }
+            Op::MoveRightToEnd => {
+                self.line("call $move_right_to_end");
+                self.set_success("i32.const 1");
+            }
+            Op::RepeatMoveUp(n) => {
+                self.line(&format!("i32.const {n}"));
+                self.set_success("call $repeat_move_up");
+            }
+            Op::NewLeftChain(n) => {
+                self.line(&format!("i32.const {n}"));
+                self.alloc("call $new_left_chain");
+                self.set_success("i32.const 1");
+            }
+            Op::NewRightChain(n) => {
+                self.line(&format!("i32.const {n}"));
+                self.alloc("call $new_right_chain");
+                self.set_success("i32.const 1");
+            }
+            Op::DeleteLeft => {
+                self.alloc("call $new_left");
+                self.line("call $move_left");
+                self.line("drop");
+                self.delete();
+            }
+            Op::DeleteRight => {
+                self.alloc("call $new_right");
+                self.line("call $move_right");
+                self.line("drop");
+                self.delete();
+            }
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.semantics.delete_root {
+            self.line("call $delete_root");
+            self.line("local.tee $success");
+            self.line("i32.const 0");
+            self.line("i32.lt_s");
+            self.line("if");
+            self.line("  i32.const 1");
+            self.line("  return");
+            self.line("end");
+        } else {
+            self.set_success("call $delete");
+        }
+    }
+
+    /// Calls a function that allocates and returns 0 when memory is
+    /// exhausted, in which case `run` returns 3.
+    fn alloc(&mut self, call: &str) {
+        self.line(call);
+        self.line("i32.eqz");
+        self.line("if");
+        self.line("  i32.const 3");
+        self.line("  return");
+        self.line("end");
+    }
+
+    /// Returns the index in the `br_table` of the segment starting at an
+    /// op, where the end of the program is the last.
+    fn target(&self, pc: usize) -> usize {
+        self.labels.range(..pc).count()
+    }
+
+    fn set_success(&mut self, value: &str) {
+        self.line(value);
+        self.line("local.set $success");
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.wat.push_str("  ");
+        }
+        self.wat.push_str(line);
+        self.wat.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmi::{Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+    use crate::tree::NodeId;
+    use crate::{corpus, EmitOptions, Program, RunStatus, Semantics, VMError, VM};
+
+    const SEMANTICS: [Semantics; 2] = [Semantics::LEAFY, Semantics::REFERENCE];
+
+    /// Runs the module compiled from a program, with linear memory limited to
+    /// `max_memory` bytes, and returns the status from `run` and the node
+    /// array, as the ids of the left child, right child, and parent of each
+    /// node.
+    fn run_wasm(prog: &Program, opts: EmitOptions, max_memory: usize) -> (i32, Vec<[u32; 3]>) {
+        let wasm = wat::parse_str(prog.to_wat(opts)).unwrap();
+        let engine = wasmi::Engine::default();
+        // Compiling validates the module.
+        let module = Module::new(&engine, &wasm[..]).unwrap();
+        let limits = StoreLimitsBuilder::new().memory_size(max_memory).build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits: &mut StoreLimits| limits);
+        let instance = Linker::<StoreLimits>::new(&engine)
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        let run = instance.get_typed_func::<(), i32>(&store, "run").unwrap();
+        let status = run.call(&mut store, ()).unwrap();
+        let tree = instance
+            .get_typed_func::<(), (i32, i32)>(&store, "tree")
+            .unwrap();
+        let (addr, len) = tree.call(&mut store, ()).unwrap();
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        let bytes = &memory.data(&store)[addr as usize..][..len as usize * 12];
+        let nodes = bytes
+            .chunks_exact(12)
+            .map(|node| {
+                let id = |i: usize| u32::from_le_bytes(node[i..i + 4].try_into().unwrap());
+                [id(0), id(4), id(8)]
+            })
+            .collect();
+        (status, nodes)
+    }
+
+    /// Runs a program on the VM, or returns `None` if it does not stop within
+    /// the step limit.
+    fn run_vm(
+        prog: &Program,
+        semantics: Semantics,
+        max_nodes: Option<usize>,
+    ) -> Option<(i32, Vec<[u32; 3]>)> {
+        let mut vm = VM::with_semantics(prog.clone(), semantics);
+        vm.set_max_nodes(max_nodes);
+        let status = match vm.run_for(10_000_000) {
+            Ok(RunStatus::Finished) | Err(VMError::Terminated) => 0,
+            Ok(RunStatus::OutOfFuel) => return None,
+            Err(VMError::DeletedTreeRoot) => 1,
+            Err(VMError::LoopStackUnderflow) => 2,
+            Err(VMError::OutOfMemory(_)) => 3,
+        };
+        let id = |id: Option<NodeId>| id.map_or(0, |id| id.0.get());
+        let nodes = vm
+            .tree
+            .unrooted()
+            .nodes
+            .iter()
+            .map(|node| [id(node.left), id(node.right), id(node.parent)])
+            .collect();
+        Some((status, nodes))
+    }
+
+    fn check(name: &str, prog: &Program) {
+        for semantics in SEMANTICS {
+            // Some examples loop forever under the reference semantics.
+            let Some(expected) = run_vm(prog, semantics, None) else {
+                continue;
+            };
+            for optimize in [false, true] {
+                let opts = EmitOptions {
+                    semantics,
+                    optimize,
+                };
+                let actual = run_wasm(prog, opts, usize::MAX);
+                assert_eq!(actual, expected, "{name} {opts:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn wasm_corpus() {
+        for (name, prog) in corpus::programs() {
+            check(&name, &prog);
+        }
+    }
+
+    #[test]
+    fn wasm_errors() {
+        for src in ["", "-", "+<{^-", "}}<", "+<(?)(^?+)*>(-?)", "+<-+<*>-"] {
+            check(src, &Program::parse(src).unwrap());
+        }
+    }
+
+    #[test]
+    fn wasm_out_of_memory() {
+        let max_memory = 1 << 20;
+        // Grows the tree or the root stack forever.
+        for src in ["+<(+<)", "+<+<(+<+<)", "+({)"] {
+            let prog = Program::parse(src).unwrap();
+            for semantics in SEMANTICS {
+                let opts = EmitOptions {
+                    semantics,
+                    optimize: false,
+                };
+                let (status, nodes) = run_wasm(&prog, opts, max_memory);
+                assert_eq!(status, 3, "{src} {opts:?}");
+                // Up to the failing instruction, it matches the VM with the
+                // node limit it reached.
+                if src.contains('<') {
+                    let expected = run_vm(&prog, semantics, Some(nodes.len()));
+                    assert_eq!(Some((status, nodes)), expected, "{src} {opts:?}");
+                }
+                let opts = EmitOptions {
+                    semantics,
+                    optimize: true,
+                };
+                assert_eq!(run_wasm(&prog, opts, max_memory).0, 3, "{src} {opts:?}");
+            }
+        }
+    }
+}
@@ -1,8 +1,16 @@
 mod ast;
+mod bf;
+#[cfg(test)]
+mod corpus;
+mod difftest;
+mod emit;
 mod meta;
 pub mod tree;
 mod vm;
 
 pub use ast::*;
+pub use bf::*;
+pub use difftest::*;
+pub use emit::*;
 pub use meta::*;
 pub use vm::*;
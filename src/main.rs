@@ -1,48 +1,517 @@
-use std::ffi::OsStr;
-use std::fs::File;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
 use std::io::Read;
 use std::path::Path;
 use std::process;
+use std::str::FromStr;
 use std::{env, io};
 
-use leafy::{Program, VM};
+use leafy::{
+    compile_bf_source, BfError, BfMachine, BfOptions, Cst, DiffTestError, EmitOptions, Engine,
+    FormatOptions, IoHandling, ParseError, Program, RunStatus, Semantics, Tape, VM,
+};
 
 fn main() {
-    let mut args = env::args_os();
-    if args.len() > 2 {
-        let name = env::current_exe().ok();
-        let name = name
-            .as_ref()
-            .map(Path::new)
-            .and_then(Path::file_name)
-            .and_then(OsStr::to_str)
-            .unwrap_or("leaf");
-        eprintln!("Usage: {name} [program]");
+    let mut args = env::args_os().skip(1).collect::<Vec<_>>();
+    let command = match args.first().and_then(|arg| arg.to_str()) {
+        Some(
+            command @ ("run" | "check" | "fmt" | "difftest" | "compile" | "bf2leaf" | "leaf2bf"),
+        ) => {
+            let command = command.to_owned();
+            args.remove(0);
+            command
+        }
+        _ => "run".to_owned(),
+    };
+    let args = Args(args);
+    match &*command {
+        "run" => run(args),
+        "check" => check(args),
+        "fmt" => fmt(args),
+        "difftest" => difftest(args),
+        "compile" => compile(args),
+        "bf2leaf" => bf2leaf(args),
+        "leaf2bf" => leaf2bf(args),
+        _ => unreachable!(),
+    }
+}
+
+fn run(mut args: Args) {
+    let semantics = semantics(&mut args).unwrap_or_default();
+    let max_steps = args.value::<u64>("--max-steps");
+    let max_nodes = args.value::<usize>("--max-nodes");
+    let meta = args.value::<usize>("--meta").unwrap_or(0);
+    let optimize = args.flag("--optimize");
+    let engine = engine(&mut args).unwrap_or_default();
+    let output = args.value::<String>("--output");
+    let output = match output.as_deref() {
+        None | Some("dot") => Output::Dot,
+        Some("tape") => Output::Tape,
+        Some("bytes") => Output::Bytes,
+        Some(other) => {
+            eprintln!("invalid value for --output: {other}");
+            process::exit(2);
+        }
+    };
+    if meta > 1 {
+        eprintln!("--meta is at most 1, since leaf.leaf uses `?`, which it does not implement");
         process::exit(2);
     }
+    let filename = args.finish();
+    let src = read_source(filename.as_ref());
+    let mut prog = parse(filename.as_ref(), &src);
+    // Interpret the program in a tower of self-interpreters.
+    for _ in 0..meta {
+        prog = match prog.wrap_meta() {
+            Ok(prog) => prog,
+            Err(err) => {
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        };
+    }
+    let mut vm = if optimize {
+        VM::optimized(prog, semantics)
+    } else {
+        VM::with_semantics(prog, semantics)
+    };
+    vm.set_max_nodes(max_nodes);
+    if let Err(err) = vm.set_engine(engine) {
+        eprintln!("compiling for the engine: {err}");
+        process::exit(1);
+    }
+    let res = match max_steps {
+        Some(steps) => vm.run_for(steps),
+        None => vm.run().map(|()| RunStatus::Finished),
+    };
+    match res {
+        Ok(RunStatus::Finished) => {}
+        Ok(RunStatus::OutOfFuel) => {
+            eprintln!("exceeded step limit of {}", max_steps.unwrap());
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    }
+    for level in (0..meta).rev() {
+        vm = match vm.tree().parse_meta_vm() {
+            Ok(vm) => vm,
+            Err(err) => {
+                eprintln!("decoding meta level {level}: {err}");
+                process::exit(1);
+            }
+        };
+    }
+    match output {
+        Output::Dot => print!("{}", vm.tree().dump_dot_to_string()),
+        Output::Tape | Output::Bytes => match vm.tree().decode_bf_tape() {
+            Ok(tape) if output == Output::Bytes => println!("{tape:#}"),
+            Ok(tape) => println!("{tape}"),
+            Err(err) => {
+                eprintln!("decoding Brainfuck tape: {err}");
+                process::exit(1);
+            }
+        },
+    }
+}
 
-    let mut src = String::new();
-    let res = if let Some(filename) = args.nth(1) {
-        File::open(filename).and_then(|mut f| f.read_to_string(&mut src))
+/// How `run` prints the final tree.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Dot,
+    /// As a Brainfuck tape of numbers.
+    Tape,
+    /// As a Brainfuck tape of characters.
+    Bytes,
+}
+
+fn check(args: Args) {
+    let filename = args.finish();
+    let src = read_source(filename.as_ref());
+    let (_, _, errors) = Program::parse_recovering(&src);
+    report_errors(filename.as_ref(), &src, &errors);
+    if !errors.is_empty() {
+        let s = if errors.len() == 1 { "" } else { "s" };
+        eprintln!("{} error{s}", errors.len());
+        process::exit(1);
+    }
+}
+
+/// Formats a program in place, or from stdin to stdout. With `--check`, it
+/// only reports whether the program is formatted.
+fn fmt(mut args: Args) {
+    let check = args.flag("--check");
+    let mut opts = FormatOptions::default();
+    if let Some(width) = args.value("--width") {
+        opts.max_width = width;
+    }
+    if let Some(indent) = args.value("--indent") {
+        opts.indent = indent;
+    }
+    let filename = args.finish();
+    let src = read_source(filename.as_ref());
+    parse(filename.as_ref(), &src);
+    let formatted = Cst::parse(&src).format(&opts);
+    if check {
+        if formatted != src {
+            eprintln!("{}: not formatted", display_name(filename.as_ref()));
+            process::exit(1);
+        }
+    } else if let Some(filename) = &filename {
+        if formatted != src {
+            if let Err(err) = fs::write(filename, formatted) {
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        }
     } else {
-        io::stdin().lock().read_to_string(&mut src)
+        print!("{formatted}");
+    }
+}
+
+/// Runs programs both natively and through `leaf.leaf` and reports those that
+/// differ. Programs using `?`, which `leaf.leaf` does not implement, are
+/// skipped. Directories are searched for `.leaf` files. With `--engine`, it
+/// instead compares that engine with the interpreter.
+fn difftest(mut args: Args) {
+    let max_steps = args.value("--max-steps").unwrap_or(100_000_000);
+    let engine = engine(&mut args);
+    let semantics = semantics(&mut args);
+    if semantics.is_some() && engine.is_none() {
+        eprintln!("--semantics requires --engine");
+        process::exit(2);
+    }
+    let mut filenames = Vec::new();
+    for path in args.finish_all() {
+        if Path::new(&path).is_dir() {
+            let entries = fs::read_dir(&path).and_then(|dir| dir.collect::<Result<Vec<_>, _>>());
+            let mut entries = entries.unwrap_or_else(|err| {
+                eprintln!("{err}");
+                process::exit(1);
+            });
+            entries.sort_by_key(|entry| entry.file_name());
+            filenames.extend(
+                entries
+                    .into_iter()
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension() == Some(OsStr::new("leaf")))
+                    .map(OsString::from),
+            );
+        } else {
+            filenames.push(path);
+        }
+    }
+    let filenames = if filenames.is_empty() {
+        vec![None]
+    } else {
+        filenames.into_iter().map(Some).collect()
     };
-    if let Err(err) = res {
-        eprintln!("{err}");
+
+    let (mut failed, mut skipped) = (0, 0);
+    for filename in &filenames {
+        let src = read_source(filename.as_ref());
+        let (prog, _, errors) = Program::parse_recovering(&src);
+        if !errors.is_empty() {
+            report_errors(filename.as_ref(), &src, &errors);
+            failed += 1;
+            continue;
+        }
+        let name = display_name(filename.as_ref());
+        let res = match engine {
+            Some(engine) => {
+                let semantics = semantics.unwrap_or_default();
+                leafy::difftest_engine(&prog, semantics, engine, max_steps)
+                    .map_err(|err| err.to_string())
+            }
+            None => match leafy::difftest(&prog, max_steps) {
+                Err(err @ DiffTestError::Unsupported(_)) => {
+                    println!("{name}: skipped: {err}");
+                    skipped += 1;
+                    continue;
+                }
+                res => res.map_err(|err| err.to_string()),
+            },
+        };
+        match res {
+            Ok(()) => println!("{name}: ok"),
+            Err(err) => {
+                println!("{name}: {err}");
+                failed += 1;
+            }
+        }
+    }
+    let passed = filenames.len() - failed - skipped;
+    if skipped == 0 {
+        println!("{passed} passed, {failed} failed");
+    } else {
+        println!("{passed} passed, {failed} failed, {skipped} skipped");
+    }
+    if failed != 0 {
         process::exit(1);
     }
+}
+
+fn semantics(args: &mut Args) -> Option<Semantics> {
+    match args.value::<String>("--semantics").as_deref() {
+        None => None,
+        Some("leafy") => Some(Semantics::LEAFY),
+        Some("reference") => Some(Semantics::REFERENCE),
+        Some(other) => {
+            eprintln!("invalid value for --semantics: {other}");
+            process::exit(2);
+        }
+    }
+}
+
+fn engine(args: &mut Args) -> Option<Engine> {
+    match args.value::<String>("--engine").as_deref() {
+        None => None,
+        Some("interpreter") => Some(Engine::Interpreter),
+        Some("threaded") => Some(Engine::Threaded),
+        #[cfg(feature = "jit")]
+        Some("jit") => Some(Engine::Jit),
+        Some(other) => {
+            eprintln!("invalid value for --engine: {other}");
+            process::exit(2);
+        }
+    }
+}
+
+/// Compiles a Leaf program ahead of time to another language.
+fn compile(mut args: Args) {
+    let opts = EmitOptions {
+        semantics: semantics(&mut args).unwrap_or_default(),
+        optimize: args.flag("--optimize"),
+    };
+    let emit = match args.value::<String>("--emit").as_deref() {
+        Some("c") => Emit::C,
+        Some("rust") => Emit::Rust,
+        Some("wat") => Emit::Wat,
+        Some(other) => {
+            eprintln!("invalid value for --emit: {other}");
+            process::exit(2);
+        }
+        None => usage(),
+    };
+    if emit == Emit::Rust && opts != EmitOptions::default() {
+        eprintln!("--emit rust only supports --semantics leafy, without --optimize");
+        process::exit(2);
+    }
+    let filename = args.finish();
+    let src = read_source(filename.as_ref());
+    let prog = parse(filename.as_ref(), &src);
+    match emit {
+        Emit::C => print!("{}", prog.to_c(opts)),
+        Emit::Rust => print!("{}", prog.to_rust("run")),
+        Emit::Wat => print!("{}", prog.to_wat(opts)),
+    }
+}
 
-    let prog = match Program::parse(&src) {
-        Ok(prog) => prog,
+/// The language that `compile` emits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    C,
+    /// A Rust function named `run`.
+    Rust,
+    /// A WebAssembly text module.
+    Wat,
+}
+
+/// Compiles a Brainfuck program to Leaf.
+fn bf2leaf(mut args: Args) {
+    let mut opts = BfOptions {
+        wrapping: args.flag("--wrapping"),
+        ..BfOptions::default()
+    };
+    if let Some(cells) = args.value("--bounded") {
+        opts.tape = Tape::Bounded(cells);
+    }
+    opts.io = match args.value::<String>("--io").as_deref() {
+        None | Some("error") => IoHandling::Error,
+        Some("ignore") => IoHandling::Ignore,
+        Some(other) => {
+            eprintln!("invalid value for --io: {other}");
+            process::exit(2);
+        }
+    };
+    let filename = args.finish();
+    let src = read_source(filename.as_ref());
+    match compile_bf_source(&src, &opts) {
+        Ok(leaf) => print!("{leaf}"),
+        Err(err) => {
+            let filename = display_name(filename.as_ref());
+            match err {
+                BfError::UnopenedLoop(pos) | BfError::UnclosedLoop(pos) | BfError::Io(_, pos) => {
+                    eprint!("{filename}: {err}\n{}", pos.excerpt(&src));
+                }
+                BfError::EmptyTape | BfError::CellBits(_) => eprintln!("{err}"),
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// Compiles a Leaf program to Brainfuck. With `--run`, runs it with the
+/// built-in interpreter and prints the decoded final tree instead.
+fn leaf2bf(mut args: Args) {
+    let run = args.flag("--run");
+    let cell_bits = args.value::<u32>("--cell-bits");
+    if cell_bits.is_some() && !run {
+        eprintln!("--cell-bits requires --run");
+        process::exit(2);
+    }
+    let filename = args.finish();
+    let src = read_source(filename.as_ref());
+    let prog = parse(filename.as_ref(), &src);
+    let bf = prog.to_bf();
+    if !run {
+        println!("{bf}");
+        return;
+    }
+    let mut machine = match BfMachine::new(&bf, cell_bits.unwrap_or(64)) {
+        Ok(machine) => machine,
         Err(err) => {
             eprintln!("{err}");
+            process::exit(2);
+        }
+    };
+    if let Err(err) = machine.run() {
+        eprintln!("{err}");
+        process::exit(1);
+    }
+    match prog.decode_bf_state(machine.tape()) {
+        Ok((tree, _)) => print!("{}", tree.dump_dot_to_string()),
+        Err(err) => {
+            eprintln!("decoding Brainfuck tape: {err}");
             process::exit(1);
         }
+    }
+}
+
+/// Command-line arguments after the subcommand.
+struct Args(Vec<OsString>);
+
+impl Args {
+    /// Removes a flag, returning whether it was present.
+    fn flag(&mut self, name: &str) -> bool {
+        let len = self.0.len();
+        self.0.retain(|arg| arg != name);
+        self.0.len() != len
+    }
+
+    /// Removes a flag with a value, given as `--name value` or `--name=value`.
+    fn value<T: FromStr>(&mut self, name: &str) -> Option<T> {
+        let i = self.0.iter().position(|arg| {
+            arg == name
+                || arg
+                    .to_str()
+                    .is_some_and(|arg| arg.strip_prefix(name).is_some_and(|v| v.starts_with('=')))
+        })?;
+        let arg = self.0.remove(i);
+        let value = if arg == name {
+            if i >= self.0.len() {
+                usage();
+            }
+            self.0.remove(i)
+        } else {
+            OsString::from(&arg.to_str().unwrap()[name.len() + 1..])
+        };
+        match value.to_str().and_then(|v| v.parse().ok()) {
+            Some(value) => Some(value),
+            None => {
+                eprintln!("invalid value for {name}: {}", value.to_string_lossy());
+                process::exit(2);
+            }
+        }
+    }
+
+    /// Returns the optional program filename, after all flags have been taken.
+    fn finish(self) -> Option<OsString> {
+        let mut args = self.0.into_iter();
+        let filename = args.next();
+        let is_flag = |arg: &OsString| arg.to_str().is_some_and(|arg| arg.starts_with("--"));
+        if args.next().is_some() || filename.as_ref().is_some_and(is_flag) {
+            usage();
+        }
+        filename
+    }
+
+    /// Returns the remaining arguments, after all flags have been taken.
+    fn finish_all(self) -> Vec<OsString> {
+        if self
+            .0
+            .iter()
+            .any(|arg| arg.to_str().is_some_and(|arg| arg.starts_with("--")))
+        {
+            usage();
+        }
+        self.0
+    }
+}
+
+fn usage() -> ! {
+    let name = env::current_exe().ok();
+    let name = name
+        .as_ref()
+        .map(Path::new)
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str)
+        .unwrap_or("leaf");
+    let pad = " ".repeat(name.len() + 14);
+    let dpad = " ".repeat(name.len() + 17);
+    let cpad = " ".repeat(name.len() + 16);
+    let jit = if cfg!(feature = "jit") { "|jit" } else { "" };
+    eprintln!("Usage: {name} [run] [--semantics leafy|reference] [--max-steps N]");
+    eprintln!("{pad}[--max-nodes N] [--meta N] [--optimize]");
+    eprintln!("{pad}[--engine interpreter|threaded{jit}]");
+    eprintln!("{pad}[--output dot|tape|bytes] [program]");
+    eprintln!("       {name} check [program]");
+    eprintln!("       {name} fmt [--check] [--width N] [--indent N] [program]");
+    eprintln!("       {name} difftest [--max-steps N] [--engine interpreter|threaded{jit}]");
+    eprintln!("{dpad}[--semantics leafy|reference] [program|dir]...");
+    eprintln!("       {name} compile --emit c|rust|wat [--semantics leafy|reference] [--optimize]");
+    eprintln!("{cpad}[program]");
+    eprintln!("       {name} bf2leaf [--bounded N] [--wrapping] [--io error|ignore] [program]");
+    eprintln!("       {name} leaf2bf [--run] [--cell-bits N] [program]");
+    process::exit(2);
+}
+
+fn read_source(filename: Option<&OsString>) -> String {
+    let mut src = String::new();
+    let res = if let Some(filename) = filename {
+        File::open(filename).and_then(|mut f| f.read_to_string(&mut src))
+    } else {
+        io::stdin().lock().read_to_string(&mut src)
     };
-    let mut vm = VM::new(prog);
-    if let Err(err) = vm.run() {
+    if let Err(err) = res {
         eprintln!("{err}");
         process::exit(1);
     }
-    print!("{}", vm.tree().dump_dot_to_string());
+    src
+}
+
+/// Parses a program, exiting with all errors reported if it is malformed.
+fn parse(filename: Option<&OsString>, src: &str) -> Program {
+    let (prog, _, errors) = Program::parse_recovering(src);
+    if !errors.is_empty() {
+        report_errors(filename, src, &errors);
+        process::exit(1);
+    }
+    prog
+}
+
+fn report_errors(filename: Option<&OsString>, src: &str, errors: &[ParseError]) {
+    let filename = display_name(filename);
+    for err in errors {
+        match err.pos() {
+            Some(pos) => eprint!("{filename}: {err}\n{}", pos.excerpt(src)),
+            None => eprintln!("{filename}: {err}"),
+        }
+    }
+}
+
+fn display_name(filename: Option<&OsString>) -> String {
+    filename.map_or("<stdin>".into(), |f| f.to_string_lossy().into_owned())
 }
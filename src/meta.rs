@@ -1,7 +1,10 @@
+use std::fmt::Write;
+use std::iter;
+
 use thiserror::Error;
 
-use crate::tree::RootedTree;
-use crate::{Inst, ParseError, Program, VM};
+use crate::tree::{MultiTree, NodeId, RootedTree};
+use crate::{Code, Inst, ParseError, Program, Semantics, VM};
 
 #[derive(Error, Clone, Debug, PartialEq, Eq)]
 pub enum ParseMetaVMError {
@@ -15,16 +18,54 @@ pub enum ParseMetaVMError {
     MissingTree,
     #[error("data after tree")]
     DataAfterTree,
+    #[error("cursor is not at an instruction")]
+    InvalidPc,
+    #[error("root is not at a loop head")]
+    InvalidLoopStack,
+    #[error("invalid data tree")]
+    InvalidTree,
+}
+
+/// An error from wrapping a program in `leaf.leaf`.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum WrapMetaError {
+    /// The program uses `?`, which `leaf.leaf` does not implement, so the
+    /// wrapped program would not terminate. This includes programs already
+    /// wrapped, since `leaf.leaf` itself uses `?`.
+    #[error("program uses `?`, which leaf.leaf does not implement")]
+    Unsupported,
+}
+
+/// The self-interpreter, which interprets the program encoded before it.
+const LEAF_LEAF: &str = include_str!("../programs/leaf.leaf");
+
+/// A node of the data tree, as encoded by `leaf.leaf`.
+struct MetaNode {
+    left: Option<NodeId>,
+    right: Option<NodeId>,
+    /// The cursor is in the left subtree.
+    left_flag: bool,
+    /// The cursor is in the right subtree.
+    right_flag: bool,
+    /// The number of times the node is on the root stack.
+    root_count: usize,
 }
 
 impl RootedTree {
+    /// Decodes the state of the VM interpreted by `leaf.leaf`. The state is
+    /// only consistent between instructions of the interpreted program, when
+    /// the cursor is on the instruction to execute next and there is a root
+    /// at each open loop head, and the decoded VM resumes from there. The
+    /// finished state decodes to a terminated VM.
     pub fn parse_meta_vm(&self) -> Result<VM, ParseMetaVMError> {
-        let mut view = self.unrooted().view(self.root_stack()[0]);
+        let mut view = self.unrooted().view(self.root());
 
         let mut prog = Vec::new();
+        let mut spine = Vec::new();
         let mut pc = 0;
         loop {
             let left = view.left();
+            spine.push(view.cursor());
             if !view.move_right() {
                 return Err(ParseMetaVMError::MissingTree);
             }
@@ -32,21 +73,9 @@ impl RootedTree {
                 let value = view
                     .at(left)
                     .count_left_only()
-                    .ok_or_else(|| ParseMetaVMError::InvalidNumber(pc))?;
-                let opcode = match value + 1 {
-                    1 => Inst::NewLeft,
-                    2 => Inst::NewRight,
-                    3 => Inst::MoveLeft,
-                    4 => Inst::MoveRight,
-                    5 => Inst::MoveUp,
-                    6 => Inst::LoopHead(usize::MAX),
-                    7 => Inst::LoopTail,
-                    8 => Inst::PushRoot,
-                    9 => Inst::PopRoot,
-                    10 => Inst::Delete,
-                    11 => Inst::Break,
-                    _ => return Err(ParseMetaVMError::InvalidOpcode(pc)),
-                };
+                    .ok_or(ParseMetaVMError::InvalidNumber(pc))?;
+                let opcode =
+                    Inst::from_meta_opcode(value + 1).ok_or(ParseMetaVMError::InvalidOpcode(pc))?;
                 prog.push(opcode);
             } else {
                 break;
@@ -55,6 +84,26 @@ impl RootedTree {
         }
         let prog = Program::from_insts(prog)?;
 
+        // The spine includes the node past the last instruction, where the
+        // cursor rests once the program has finished.
+        let pc = spine
+            .iter()
+            .position(|&id| id == self.cursor())
+            .ok_or(ParseMetaVMError::InvalidPc)?;
+        let loop_stack = self
+            .root_stack()
+            .iter()
+            .skip(1)
+            .map(|root| {
+                let head = spine.iter().position(|id| id == root)?;
+                match prog.get(head) {
+                    Some(&Inst::LoopHead(tail)) => Some((head, tail)),
+                    _ => None,
+                }
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(ParseMetaVMError::InvalidLoopStack)?;
+
         let success = view.left().is_some();
         if !view.move_right() {
             return Err(ParseMetaVMError::MissingTree);
@@ -66,14 +115,278 @@ impl RootedTree {
             return Err(ParseMetaVMError::MissingTree);
         }
 
-        let tree = RootedTree::new();
+        let tree = parse_meta_tree(self.unrooted(), view.cursor())?;
 
         Ok(VM {
+            code: Code::new(&prog),
             prog,
-            pc: usize::MAX,
+            pc,
             tree,
-            loop_stack: vec![],
+            loop_stack,
             success,
+            semantics: Semantics::LEAFY,
+            compiled: None,
         })
     }
 }
+
+/// Decodes the data tree rooted at `node`, along with its cursor and root
+/// stack.
+fn parse_meta_tree(meta: &MultiTree, node: NodeId) -> Result<RootedTree, ParseMetaVMError> {
+    let mut tree = MultiTree::new();
+    // A new tree has no limit on nodes.
+    let root = tree.new_node().unwrap();
+    let mut root_count = 0;
+    let mut stack = vec![(node, root)];
+    while let Some((meta_id, id)) = stack.pop() {
+        let node = parse_meta_node(meta, meta_id)?;
+        root_count += node.root_count;
+        if let Some(left) = node.left {
+            stack.push((left, tree.new_left(id).unwrap()));
+        }
+        if let Some(right) = node.right {
+            stack.push((right, tree.new_right(id).unwrap()));
+        }
+    }
+
+    // Follow the flags to the cursor. Roots are always on the way, since
+    // they are ancestors of the cursor.
+    let (mut meta_cursor, mut cursor) = (node, root);
+    let mut root_stack = Vec::new();
+    loop {
+        let node = parse_meta_node(meta, meta_cursor)?;
+        root_stack.extend(iter::repeat_n(cursor, node.root_count));
+        let (meta_next, next) = match (node.left_flag, node.right_flag) {
+            (false, false) => break,
+            (true, false) => (node.left, tree[cursor].left()),
+            (false, true) => (node.right, tree[cursor].right()),
+            (true, true) => return Err(ParseMetaVMError::InvalidTree),
+        };
+        match (meta_next, next) {
+            (Some(meta_next), Some(next)) => (meta_cursor, cursor) = (meta_next, next),
+            _ => return Err(ParseMetaVMError::InvalidTree),
+        }
+    }
+    if root_stack.len() != root_count || root_stack.first() != Some(&root) {
+        return Err(ParseMetaVMError::InvalidTree);
+    }
+    Ok(RootedTree::from_parts(tree, root, cursor, root_stack))
+}
+
+/// Decodes a node of the data tree. Its left child holds a pair of branches,
+/// each with a child node on the left and a cursor flag on the right, and its
+/// right child is a numeral of its root count.
+fn parse_meta_node(meta: &MultiTree, id: NodeId) -> Result<MetaNode, ParseMetaVMError> {
+    let node = &meta[id];
+    let branches = node.left().ok_or(ParseMetaVMError::InvalidTree)?;
+    let left = meta[branches].left().ok_or(ParseMetaVMError::InvalidTree)?;
+    let right = meta[branches]
+        .right()
+        .ok_or(ParseMetaVMError::InvalidTree)?;
+    let root_count = match node.right() {
+        Some(count) => meta.view(count).count_right_only().map(|n| n + 1),
+        None => Some(0),
+    };
+    Ok(MetaNode {
+        left: meta[left].left(),
+        right: meta[right].left(),
+        left_flag: meta[left].right().is_some(),
+        right_flag: meta[right].right().is_some(),
+        root_count: root_count.ok_or(ParseMetaVMError::InvalidTree)?,
+    })
+}
+
+impl Program {
+    /// Encodes the program in the layout expected by `leaf.leaf`: a right
+    /// spine starting at the root, with each instruction as a numeral hanging
+    /// to the left of a spine node. The cursor is at the end of the spine,
+    /// where `leaf.leaf` initializes the rest of its state.
+    pub fn encode_meta(&self) -> RootedTree {
+        let mut tree = MultiTree::new();
+        // A new tree has no limit on nodes.
+        let root = tree.new_node().unwrap();
+        let mut spine = root;
+        for &inst in self.insts() {
+            let mut node = tree.new_left(spine).unwrap();
+            for _ in 1..inst.meta_opcode() {
+                node = tree.new_left(node).unwrap();
+            }
+            spine = tree.new_right(spine).unwrap();
+        }
+        RootedTree::from_parts(tree, root, spine, vec![root])
+    }
+
+    /// Emits Leaf source that builds [`Program::encode_meta`], with one
+    /// instruction per line, in the style of the examples in `leaf.leaf`.
+    /// Prepending it to the interpreter in `leaf.leaf`, from `Initialization`
+    /// on, interprets the program.
+    pub fn encode_meta_source(&self) -> String {
+        let mut src = String::new();
+        for &inst in self.insts() {
+            let opcode = inst.meta_opcode();
+            let chain = vec!["+"; opcode].join("<");
+            let (open, close) = if opcode < 5 {
+                (" ", "^".repeat(opcode - 1))
+            } else {
+                ("{", "(^)}".to_owned())
+            };
+            let name = inst.meta_name();
+            writeln!(src, "{opcode:<2} {open}{chain:<22}{close:<4} *> {name}").unwrap();
+        }
+        src
+    }
+
+    /// Wraps the program in the self-interpreter `leaf.leaf`, yielding a
+    /// program that interprets it. The example program encoded in the header
+    /// of `leaf.leaf` is left out. Running the result and decoding its tree
+    /// with [`RootedTree::parse_meta_vm`] gives the state of this program.
+    ///
+    /// `leaf.leaf` does not yet implement `?`, so programs using it, including
+    /// `leaf.leaf` itself, are rejected with [`WrapMetaError::Unsupported`].
+    pub fn wrap_meta(&self) -> Result<Program, WrapMetaError> {
+        if self.insts().contains(&Inst::Break) {
+            return Err(WrapMetaError::Unsupported);
+        }
+        let start = LEAF_LEAF
+            .find("Initialization:")
+            .expect("leaf.leaf is missing its initialization");
+        let src = self.encode_meta_source() + &LEAF_LEAF[start..];
+        Ok(Program::parse(&src).expect("leaf.leaf is malformed"))
+    }
+}
+
+impl Inst {
+    /// Returns the numeral for the instruction in `leaf.leaf`.
+    pub fn meta_opcode(self) -> usize {
+        match self {
+            Inst::NewLeft => 1,
+            Inst::NewRight => 2,
+            Inst::MoveLeft => 3,
+            Inst::MoveRight => 4,
+            Inst::MoveUp => 5,
+            Inst::LoopHead(_) => 6,
+            Inst::LoopTail => 7,
+            Inst::PushRoot => 8,
+            Inst::PopRoot => 9,
+            Inst::Delete => 10,
+            Inst::Break => 11,
+        }
+    }
+
+    /// Decodes a numeral from `leaf.leaf`. Loop heads are left unresolved.
+    pub fn from_meta_opcode(opcode: usize) -> Option<Self> {
+        Some(match opcode {
+            1 => Inst::NewLeft,
+            2 => Inst::NewRight,
+            3 => Inst::MoveLeft,
+            4 => Inst::MoveRight,
+            5 => Inst::MoveUp,
+            6 => Inst::LoopHead(usize::MAX),
+            7 => Inst::LoopTail,
+            8 => Inst::PushRoot,
+            9 => Inst::PopRoot,
+            10 => Inst::Delete,
+            11 => Inst::Break,
+            _ => return None,
+        })
+    }
+
+    fn meta_name(self) -> &'static str {
+        match self {
+            Inst::NewLeft => "new left",
+            Inst::NewRight => "new right",
+            Inst::MoveLeft => "move left",
+            Inst::MoveRight => "move right",
+            Inst::MoveUp => "move up",
+            Inst::LoopHead(_) => "loop head",
+            Inst::LoopTail => "loop tail",
+            Inst::PushRoot => "push root",
+            Inst::PopRoot => "pop root",
+            Inst::Delete => "delete",
+            Inst::Break => "break",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{corpus, RunStatus, VMError};
+
+    const PROGRAMS: [&str; 4] = ["", "+<*>(^)>{<+<^}*", "+<+<{(-)}{*>{", "*>+<(^)-(<)-}"];
+
+    /// Decodes the instructions on the spine of an encoded program.
+    fn decode_spine(tree: &RootedTree) -> Program {
+        let mut view = tree.unrooted().view(tree.root());
+        let mut insts = Vec::new();
+        while let Some(left) = view.left() {
+            let value = view.at(left).count_left_only().unwrap();
+            insts.push(Inst::from_meta_opcode(value + 1).unwrap());
+            assert!(view.move_right());
+        }
+        assert_eq!(view.cursor(), tree.cursor());
+        Program::from_insts(insts).unwrap()
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let programs = PROGRAMS.iter().map(|src| Program::parse(src).unwrap());
+        let programs = programs.chain(corpus::programs().into_iter().map(|(_, prog)| prog));
+        for prog in programs {
+            let tree = prog.encode_meta();
+            assert_eq!(decode_spine(&tree), prog);
+        }
+    }
+
+    #[test]
+    fn encode_source() {
+        for src in PROGRAMS {
+            let prog = Program::parse(src).unwrap();
+            let mut vm = VM::new(Program::parse(&prog.encode_meta_source()).unwrap());
+            _ = vm.run();
+            assert_eq!(decode_spine(vm.tree()), prog, "{src}");
+        }
+    }
+
+    #[test]
+    fn wrap_round_trip() {
+        for src in PROGRAMS {
+            let prog = Program::parse(src).unwrap();
+            let mut native = VM::new(prog.clone());
+            _ = native.run();
+
+            // Run in chunks, to resume the interpreter repeatedly.
+            let mut meta = VM::new(prog.wrap_meta().unwrap());
+            while meta.run_for(1000) == Ok(RunStatus::OutOfFuel) {}
+            let mut decoded = meta.tree().parse_meta_vm().unwrap();
+            assert_eq!(decoded.program(), &prog, "{src}");
+            assert_eq!(decoded.pc(), prog.len(), "{src}");
+            assert_eq!(decoded.success, native.success, "{src}");
+            // This compares the cursors and root stacks, too.
+            assert_eq!(decoded.tree().diff(native.tree()), None, "{src}");
+            assert_eq!(decoded.step(), Err(VMError::Terminated));
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let prog = Program::parse("+<*>").unwrap();
+        // The interpreter has not yet built its state after the program.
+        assert_eq!(
+            prog.encode_meta().parse_meta_vm().unwrap_err(),
+            ParseMetaVMError::MissingTree,
+        );
+        assert_eq!(
+            RootedTree::new().parse_meta_vm().unwrap_err(),
+            ParseMetaVMError::MissingTree,
+        );
+    }
+
+    #[test]
+    fn wrap_unsupported() {
+        let prog = Program::parse("+(<?)").unwrap();
+        assert_eq!(prog.wrap_meta(), Err(WrapMetaError::Unsupported));
+        let wrapped = Program::parse("+<").unwrap().wrap_meta().unwrap();
+        assert_eq!(wrapped.wrap_meta(), Err(WrapMetaError::Unsupported));
+    }
+}
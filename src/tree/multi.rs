@@ -1,36 +1,109 @@
 use std::collections::vec_deque::VecDeque;
 use std::fmt;
-use std::num::NonZeroUsize;
+use std::mem;
+use std::num::NonZeroU32;
 use std::ops::{Index, IndexMut};
 
+use thiserror::Error;
+
 use crate::tree::TreeView;
 
 #[derive(Clone, Debug)]
 pub struct MultiTree {
-    nodes: Vec<Node>,
-    free: Option<NodeId>,
+    pub(crate) nodes: Vec<Node>,
+    /// The head of the list of freed nodes, linked through their parents.
+    /// The children of a freed node are freed when it is reused.
+    pub(crate) free: Option<NodeId>,
+    max_nodes: Option<usize>,
+    /// The number of ids that can be allocated, which is [`MAX_IDS`] except
+    /// in tests, where it is lowered to reach it.
+    max_ids: usize,
 }
 
+/// A node in the arena, with the layout of three `u32` ids, where 0 is none.
+#[repr(C)]
 #[derive(Clone, Debug)]
 pub struct Node {
-    left: Option<NodeId>,
-    right: Option<NodeId>,
-    parent: Option<NodeId>,
+    pub(crate) left: Option<NodeId>,
+    pub(crate) right: Option<NodeId>,
+    pub(crate) parent: Option<NodeId>,
 }
 
+/// A 32-bit index of a node, so that a node with its three links fits in 12
+/// bytes.
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub struct NodeId(NonZeroUsize);
+pub struct NodeId(pub(crate) NonZeroU32);
+
+const _: () = assert!(mem::size_of::<Node>() == 12);
+
+/// The number of nodes addressable by a [`NodeId`].
+const MAX_IDS: usize = u32::MAX as usize;
+
+/// The arena has reached its maximum number of nodes, either as set with
+/// [`MultiTree::set_max_nodes`] or as addressable by a [`NodeId`].
+#[derive(Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[error("out of memory")]
+pub struct OutOfMemory;
 
 impl MultiTree {
     pub fn new() -> Self {
         MultiTree {
             nodes: Vec::new(),
             free: None,
+            max_nodes: None,
+            max_ids: MAX_IDS,
+        }
+    }
+
+    /// Limits the number of nodes allocated in the arena. Nodes already
+    /// allocated are kept.
+    ///
+    /// The limit counts allocated ids, but deleted nodes and their subtrees
+    /// are reused before the arena grows, so creating a node only fails when
+    /// every allocated node is live. It is therefore also a limit on live
+    /// nodes, where subtrees unlinked by [`MultiTree::detach`] stay live.
+    /// Regardless of the limit, the arena holds at most `u32::MAX` nodes.
+    pub fn set_max_nodes(&mut self, max_nodes: Option<usize>) {
+        self.max_nodes = max_nodes;
+    }
+
+    pub fn max_nodes(&self) -> Option<usize> {
+        self.max_nodes
+    }
+
+    /// Returns the number of nodes allocated in the arena, including freed
+    /// nodes.
+    pub fn allocated(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns whether `n` nodes can be created without running out of memory,
+    /// counting freed nodes, which are reused, and the subtree at `replaced`,
+    /// which is freed by the first of them.
+    pub(crate) fn has_capacity(&self, n: usize, replaced: Option<NodeId>) -> bool {
+        let mut needed = n.saturating_sub(self.limit().saturating_sub(self.nodes.len()));
+        // Freed nodes are linked through their parents, but keep their
+        // children until they are reused.
+        let mut subtrees = Vec::from_iter(replaced);
+        let mut free = self.free;
+        while needed > 0 {
+            let id = if let Some(id) = subtrees.pop() {
+                id
+            } else if let Some(id) = free {
+                free = self[id].parent;
+                id
+            } else {
+                return false;
+            };
+            subtrees.extend(self[id].left);
+            subtrees.extend(self[id].right);
+            needed -= 1;
         }
+        true
     }
 
-    pub fn new_node(&mut self) -> NodeId {
+    pub fn new_node(&mut self) -> Result<NodeId, OutOfMemory> {
         if let Some(id) = self.free {
             let node = self.get_unchecked_mut(id);
             let left = node.left;
@@ -46,35 +119,53 @@ impl MultiTree {
             if let Some(left) = left {
                 self.free(left);
             }
-            id
+            Ok(id)
         } else {
+            if self.nodes.len() >= self.limit() {
+                return Err(OutOfMemory);
+            }
             let id = NodeId::new(self.nodes.len());
             self.nodes.push(Node {
                 left: None,
                 right: None,
                 parent: None,
             });
-            id
+            Ok(id)
         }
     }
 
-    pub fn new_left(&mut self, id: NodeId) {
-        let left = self.new_node_reused(self[id].left, Some(id));
+    /// Returns the number of ids that can be allocated, with the limit on
+    /// nodes and on addressable ids.
+    pub(crate) fn limit(&self) -> usize {
+        self.max_nodes
+            .map_or(self.max_ids, |max| max.min(self.max_ids))
+    }
+
+    pub fn new_left(&mut self, id: NodeId) -> Result<NodeId, OutOfMemory> {
+        let left = self.new_node_reused(self[id].left, Some(id))?;
         self.get_unchecked_mut(id).left = Some(left);
+        Ok(left)
     }
 
-    pub fn new_right(&mut self, id: NodeId) {
-        let right = self.new_node_reused(self[id].right, Some(id));
+    pub fn new_right(&mut self, id: NodeId) -> Result<NodeId, OutOfMemory> {
+        let right = self.new_node_reused(self[id].right, Some(id))?;
         self.get_unchecked_mut(id).right = Some(right);
+        Ok(right)
     }
 
-    fn new_node_reused(&mut self, id: Option<NodeId>, parent: Option<NodeId>) -> NodeId {
+    fn new_node_reused(
+        &mut self,
+        id: Option<NodeId>,
+        parent: Option<NodeId>,
+    ) -> Result<NodeId, OutOfMemory> {
+        // Freeing the old child first lets it be reused, so replacing a child
+        // never runs out of memory.
         if let Some(id) = id {
             self.free(id);
         }
-        let id = self.new_node();
+        let id = self.new_node()?;
         self[id].parent = parent;
-        id
+        Ok(id)
     }
 
     pub fn set_left(&mut self, id: NodeId, left: Option<NodeId>) {
@@ -102,9 +193,14 @@ impl MultiTree {
     }
 
     pub fn delete(&mut self, id: NodeId) {
-        let parent = self[id].parent;
+        self.detach(id);
         self.free(id);
-        if let Some(parent) = parent {
+    }
+
+    /// Unlinks a node from its parent, leaving it as the root of a separate
+    /// tree.
+    pub fn detach(&mut self, id: NodeId) {
+        if let Some(parent) = self[id].parent.take() {
             let parent = self.get_unchecked_mut(parent);
             if parent.left == Some(id) {
                 parent.left = None;
@@ -158,6 +254,12 @@ impl MultiTree {
     }
 }
 
+impl Default for MultiTree {
+    fn default() -> Self {
+        MultiTree::new()
+    }
+}
+
 impl Index<NodeId> for MultiTree {
     type Output = Node;
 
@@ -187,11 +289,104 @@ impl Node {
 }
 
 impl NodeId {
+    /// Creates an id from an index, which `new_node` keeps below `u32::MAX`.
     fn new(id: usize) -> Self {
-        NodeId(unsafe { NonZeroUsize::new_unchecked(id + 1) })
+        debug_assert!(id < MAX_IDS);
+        NodeId(unsafe { NonZeroU32::new_unchecked(id as u32 + 1) })
     }
 
     fn as_usize(self) -> usize {
-        self.0.get() - 1
+        (self.0.get() - 1) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_nodes() {
+        let mut tree = MultiTree::new();
+        tree.set_max_nodes(Some(3));
+        let root = tree.new_node().unwrap();
+        let left = tree.new_left(root).unwrap();
+        tree.new_right(left).unwrap();
+        assert_eq!(tree.new_right(root), Err(OutOfMemory));
+        assert_eq!(tree.new_node(), Err(OutOfMemory));
+        assert_eq!(tree.allocated(), 3);
+    }
+
+    #[test]
+    fn max_nodes_reuses_deleted() {
+        let mut tree = MultiTree::new();
+        tree.set_max_nodes(Some(3));
+        let root = tree.new_node().unwrap();
+        let left = tree.new_left(root).unwrap();
+        tree.new_left(left).unwrap();
+        // Deleting a subtree frees its nodes, but only reuses its children
+        // when its root is reused.
+        tree.delete(left);
+        let right = tree.new_right(root).unwrap();
+        tree.new_right(right).unwrap();
+        assert_eq!(tree.new_left(right), Err(OutOfMemory));
+        // Replacing a child reuses it.
+        for _ in 0..10 {
+            tree.new_right(right).unwrap();
+        }
+        assert_eq!(tree.allocated(), 3);
+    }
+
+    #[test]
+    fn max_nodes_counts_detached() {
+        let mut tree = MultiTree::new();
+        tree.set_max_nodes(Some(2));
+        let root = tree.new_node().unwrap();
+        let left = tree.new_left(root).unwrap();
+        tree.detach(left);
+        assert_eq!(tree.new_right(root), Err(OutOfMemory));
+    }
+
+    #[test]
+    fn has_capacity() {
+        let mut tree = MultiTree::new();
+        tree.set_max_nodes(Some(5));
+        let root = tree.new_node().unwrap();
+        let left = tree.new_left(root).unwrap();
+        tree.new_left(left).unwrap();
+        tree.new_right(left).unwrap();
+        assert!(tree.has_capacity(1, None));
+        assert!(!tree.has_capacity(2, None));
+        // Replacing the left subtree frees all three of its nodes.
+        assert!(tree.has_capacity(4, Some(left)));
+        assert!(!tree.has_capacity(5, Some(left)));
+        // So does deleting it, and its children are reused after it.
+        tree.delete(left);
+        assert!(tree.has_capacity(4, None));
+        assert!(!tree.has_capacity(5, None));
+        for _ in 0..4 {
+            tree.new_node().unwrap();
+        }
+        assert_eq!(tree.new_node(), Err(OutOfMemory));
+        tree.set_max_nodes(None);
+        assert!(tree.has_capacity(MAX_IDS - 5, None));
+        assert!(!tree.has_capacity(MAX_IDS - 4, None));
+    }
+
+    #[test]
+    fn max_ids() {
+        let mut tree = MultiTree::new();
+        tree.max_ids = 3;
+        let root = tree.new_node().unwrap();
+        let left = tree.new_left(root).unwrap();
+        assert!(tree.has_capacity(1, None));
+        tree.new_left(left).unwrap();
+        assert!(!tree.has_capacity(1, None));
+        assert_eq!(tree.new_right(root), Err(OutOfMemory));
+        // The lower of the two limits applies.
+        tree.set_max_nodes(Some(10));
+        assert_eq!(tree.new_right(root), Err(OutOfMemory));
+        tree.set_max_nodes(Some(2));
+        assert_eq!(tree.new_right(left), Err(OutOfMemory));
+        assert_eq!(tree.allocated(), 3);
     }
 }
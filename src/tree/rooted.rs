@@ -1,25 +1,44 @@
 use std::fmt;
 
-use crate::tree::{MultiTree, Node, NodeId};
+use crate::tree::{MultiTree, Node, NodeId, OutOfMemory};
 
 #[derive(Clone, Debug)]
 pub struct RootedTree {
-    tree: MultiTree,
-    cursor: NodeId,
-    root_stack: Vec<NodeId>,
+    pub(crate) tree: MultiTree,
+    root: NodeId,
+    pub(crate) cursor: NodeId,
+    pub(crate) root_stack: Vec<NodeId>,
 }
 
 impl RootedTree {
     pub fn new() -> Self {
         let mut tree = MultiTree::new();
-        let root = tree.new_node();
+        // A new tree has no limit on nodes.
+        let root = tree.new_node().unwrap();
         RootedTree {
             tree,
+            root,
             cursor: root,
             root_stack: vec![root],
         }
     }
 
+    /// Wraps a tree with a cursor and root stack, which must be nodes in the
+    /// tree with the roots ordered from `root` outward.
+    pub(crate) fn from_parts(
+        tree: MultiTree,
+        root: NodeId,
+        cursor: NodeId,
+        root_stack: Vec<NodeId>,
+    ) -> Self {
+        RootedTree {
+            tree,
+            root,
+            cursor,
+            root_stack,
+        }
+    }
+
     pub fn move_left(&mut self) -> bool {
         if let Some(left) = self.node().left() {
             self.cursor = left;
@@ -47,12 +66,44 @@ impl RootedTree {
         }
     }
 
-    pub fn new_left(&mut self) {
-        self.tree.new_left(self.cursor);
+    pub fn new_left(&mut self) -> Result<(), OutOfMemory> {
+        self.tree.new_left(self.cursor).map(|_| ())
+    }
+
+    pub fn new_right(&mut self) -> Result<(), OutOfMemory> {
+        self.tree.new_right(self.cursor).map(|_| ())
+    }
+
+    /// Creates a chain of `n` left children from the cursor and moves to the
+    /// last, like `+<` repeated `n` times. When there are not enough nodes for
+    /// the whole chain, fails without changing the tree.
+    pub(crate) fn new_left_chain(&mut self, n: usize) -> Result<(), OutOfMemory> {
+        if !self.tree.has_capacity(n, self.node().left()) {
+            return Err(OutOfMemory);
+        }
+        for _ in 0..n {
+            self.cursor = self.tree.new_left(self.cursor)?;
+        }
+        Ok(())
+    }
+
+    /// Creates a chain of `n` right children from the cursor and moves to the
+    /// last, like `*>` repeated `n` times. When there are not enough nodes for
+    /// the whole chain, fails without changing the tree.
+    pub(crate) fn new_right_chain(&mut self, n: usize) -> Result<(), OutOfMemory> {
+        if !self.tree.has_capacity(n, self.node().right()) {
+            return Err(OutOfMemory);
+        }
+        for _ in 0..n {
+            self.cursor = self.tree.new_right(self.cursor)?;
+        }
+        Ok(())
     }
 
-    pub fn new_right(&mut self) {
-        self.tree.new_right(self.cursor);
+    /// Limits the number of nodes in the tree. See
+    /// [`MultiTree::set_max_nodes`].
+    pub fn set_max_nodes(&mut self, max_nodes: Option<usize>) {
+        self.tree.set_max_nodes(max_nodes);
     }
 
     pub fn delete(&mut self) -> bool {
@@ -65,6 +116,19 @@ impl RootedTree {
         }
     }
 
+    /// Detaches the node at the cursor from its parent, even when it is a
+    /// root, and moves to the parent. Fails at the root of the tree. This is
+    /// the behaviour of `-` in the reference implementation.
+    pub fn detach(&mut self) -> bool {
+        if let Some(parent) = self.node().parent() {
+            self.tree.detach(self.cursor);
+            self.cursor = parent;
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn unrooted(&self) -> &MultiTree {
         &self.tree
     }
@@ -73,6 +137,11 @@ impl RootedTree {
         self.tree
     }
 
+    /// Returns the root of the tree, which is the first root pushed.
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
     pub fn cursor(&self) -> NodeId {
         self.cursor
     }
@@ -82,10 +151,10 @@ impl RootedTree {
     }
 
     fn parent(&self) -> Option<NodeId> {
-        if !self.at_root() {
-            let parent = self.node().parent();
-            debug_assert!(parent.is_some());
-            parent
+        // Nodes only lack a parent at a root, except for roots detached by
+        // `detach`.
+        if !self.at_root() && !self.root_stack.is_empty() {
+            self.node().parent()
         } else {
             None
         }
@@ -95,27 +164,41 @@ impl RootedTree {
         self.root_stack.push(self.cursor);
     }
 
+    /// Pops the top root, unless it is the last one.
     pub fn pop_root(&mut self) -> Option<NodeId> {
-        if self.root_stack.len() >= 1 {
+        if self.root_stack.len() > 1 {
             self.root_stack.pop()
         } else {
             None
         }
     }
 
+    /// Pops the top root, even when it is the last one. Afterwards, no node is
+    /// a root, so `move_up` and `at_root` always fail. This is the behaviour
+    /// of `}` in the reference implementation.
+    pub fn pop_last_root(&mut self) -> Option<NodeId> {
+        self.root_stack.pop()
+    }
+
     pub fn root_stack(&self) -> &[NodeId] {
         &self.root_stack
     }
 
     pub fn at_root(&self) -> bool {
-        self.cursor == self.root_stack[self.root_stack.len() - 1]
+        self.root_stack.last() == Some(&self.cursor)
     }
 
     pub fn dump_dot<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
-        self.tree.dump_dot(w, self.root_stack[0])
+        self.tree.dump_dot(w, self.root)
     }
 
     pub fn dump_dot_to_string(&self) -> String {
-        self.tree.dump_dot_to_string(self.root_stack[0])
+        self.tree.dump_dot_to_string(self.root)
+    }
+}
+
+impl Default for RootedTree {
+    fn default() -> Self {
+        RootedTree::new()
     }
 }
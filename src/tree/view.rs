@@ -13,6 +13,10 @@ impl<'a> TreeView<'a> {
         TreeView { tree, cursor }
     }
 
+    pub fn cursor(&self) -> NodeId {
+        self.cursor
+    }
+
     pub fn node(&self) -> &Node {
         self.tree.get_unchecked(self.cursor)
     }
@@ -56,7 +60,7 @@ impl<'a> TreeView<'a> {
         }
     }
 
-    pub fn at(&self, cursor: NodeId) -> TreeView {
+    pub fn at(&self, cursor: NodeId) -> TreeView<'_> {
         TreeView::new(self.tree, cursor)
     }
 
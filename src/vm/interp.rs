@@ -0,0 +1,671 @@
+use std::io;
+
+use thiserror::Error;
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+use super::jit::Jit;
+use super::threaded::Threaded;
+use crate::tree::{OutOfMemory, RootedTree};
+use crate::{Code, Op, Program};
+
+#[derive(Clone, Debug)]
+pub struct VM {
+    pub(crate) prog: Program,
+    /// The program compiled with its jumps resolved, which is executed.
+    pub(crate) code: Code,
+    pub(crate) pc: usize,
+    pub(crate) tree: RootedTree,
+    /// The heads and tails of the loops being executed. Jumps are resolved
+    /// statically, so this is only maintained with
+    /// [`Semantics::break_keeps_loop`], where loops left by `?` stay on it.
+    pub(crate) loop_stack: Vec<(usize, usize)>,
+    pub(crate) success: bool,
+    pub(crate) semantics: Semantics,
+    /// The compiled code, for engines other than the interpreter.
+    pub(crate) compiled: Option<Compiled>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Compiled {
+    Threaded(Threaded),
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    Jit(Jit),
+}
+
+/// How a [`VM`] executes its program. All give the same results and step
+/// counts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Engine {
+    /// Dispatches on each instruction as it executes.
+    #[default]
+    Interpreter,
+    /// Compiles the program ahead of time to a table of handlers, with
+    /// operands decoded and the semantics resolved, and calls through it.
+    Threaded,
+    /// Compiles the program ahead of time to x86-64 machine code. On other
+    /// targets, this falls back to the interpreter.
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+/// The behaviours in which Leafy differs from the reference implementation of
+/// Leaf, as listed in `differences.md`. Each flag opts into the reference
+/// behaviour.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Semantics {
+    /// `}` pops the topmost root, instead of failing.
+    pub pop_last_root: bool,
+    /// `-` at a root detaches it, instead of failing, and errors at the root
+    /// of the tree.
+    pub delete_root: bool,
+    /// `?` jumps past its loop without popping it from the loop stack.
+    pub break_keeps_loop: bool,
+    /// `?` outside of a loop restarts the program, instead of exiting.
+    pub break_restarts: bool,
+}
+
+/// The state of a VM after running for a bounded number of steps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RunStatus {
+    /// The program ran to completion.
+    Finished,
+    /// The step limit was reached before the program finished. Running again
+    /// resumes where it stopped.
+    OutOfFuel,
+}
+
+/// An error from executing a program. Executing never panics: instructions
+/// that cannot act, like `^` at a root or `}` with one root, instead set the
+/// success flag to false, and the remaining cases are reported here. When an
+/// instruction errors, the VM stays at that instruction.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum VMError {
+    #[error("VM has terminated")]
+    Terminated,
+    /// `-` at the root of the tree, with [`Semantics::delete_root`].
+    #[error("deleted the root of the tree")]
+    DeletedTreeRoot,
+    /// `)` when the loop stack is empty, with [`Semantics::break_keeps_loop`].
+    /// This does not happen for well-formed programs.
+    #[error("loop stack underflow")]
+    LoopStackUnderflow,
+    /// `+` or `*` when the tree has reached its limit on nodes, set with
+    /// [`VM::set_max_nodes`].
+    #[error(transparent)]
+    OutOfMemory(#[from] OutOfMemory),
+}
+
+impl VM {
+    pub fn new(prog: Program) -> Self {
+        VM::with_semantics(prog, Semantics::LEAFY)
+    }
+
+    pub fn with_semantics(prog: Program, semantics: Semantics) -> Self {
+        VM {
+            code: Code::new(&prog),
+            prog,
+            pc: 0,
+            tree: RootedTree::new(),
+            loop_stack: vec![],
+            success: false,
+            semantics,
+            compiled: None,
+        }
+    }
+
+    /// Creates a VM that executes the program with common idioms fused into
+    /// superinstructions, like `(^)` or runs of `+<`. The tree, success flag
+    /// and errors are the same as with [`VM::with_semantics`], but each
+    /// superinstruction takes one step, and [`VM::pc`] indexes the optimized
+    /// code instead of the program.
+    pub fn optimized(prog: Program, semantics: Semantics) -> Self {
+        VM {
+            code: Code::optimized(&prog),
+            ..VM::with_semantics(prog, semantics)
+        }
+    }
+
+    /// Selects the engine for executing the program, which can be changed
+    /// between runs. Fails when the operating system refuses to map code for
+    /// the JIT, in which case the engine is unchanged.
+    pub fn set_engine(&mut self, engine: Engine) -> io::Result<()> {
+        self.compiled = match engine {
+            Engine::Interpreter => None,
+            Engine::Threaded => Some(Compiled::Threaded(Threaded::compile(
+                &self.code,
+                self.semantics,
+            ))),
+            #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+            Engine::Jit => Some(Compiled::Jit(Jit::compile(&self.code, self.semantics)?)),
+            #[cfg(all(feature = "jit", not(all(target_arch = "x86_64", unix))))]
+            Engine::Jit => None,
+        };
+        Ok(())
+    }
+
+    /// Returns the engine in use, which is the interpreter when the selected
+    /// engine is unsupported.
+    pub fn engine(&self) -> Engine {
+        match self.compiled {
+            None => Engine::Interpreter,
+            Some(Compiled::Threaded(_)) => Engine::Threaded,
+            #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+            Some(Compiled::Jit(_)) => Engine::Jit,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), VMError> {
+        if let Some(compiled) = self.compiled.take() {
+            let res = match &compiled {
+                Compiled::Threaded(threaded) => threaded.run(self),
+                #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+                Compiled::Jit(jit) => jit.run(self),
+            };
+            self.compiled = Some(compiled);
+            res
+        } else if self.semantics.break_keeps_loop {
+            self.run_inline::<true>()
+        } else {
+            self.run_inline::<false>()
+        }
+    }
+
+    /// Runs for at most `steps` instructions, stopping early when the program
+    /// finishes or errors.
+    pub fn run_for(&mut self, steps: u64) -> Result<RunStatus, VMError> {
+        if let Some(compiled) = self.compiled.take() {
+            let res = match &compiled {
+                Compiled::Threaded(threaded) => threaded.run_for(self, steps),
+                #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+                Compiled::Jit(jit) => jit.run_for(self, steps),
+            };
+            self.compiled = Some(compiled);
+            res
+        } else if self.semantics.break_keeps_loop {
+            self.run_for_inline::<true>(steps)
+        } else {
+            self.run_for_inline::<false>(steps)
+        }
+    }
+
+    pub fn step(&mut self) -> Result<(), VMError> {
+        if let Some(compiled) = self.compiled.take() {
+            let res = match &compiled {
+                Compiled::Threaded(threaded) => threaded.step(self),
+                #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+                Compiled::Jit(jit) => jit.step(self),
+            };
+            self.compiled = Some(compiled);
+            res
+        } else if self.semantics.break_keeps_loop {
+            self.step_inline::<true>()
+        } else {
+            self.step_inline::<false>()
+        }
+    }
+
+    fn run_inline<const LOOP_STACK: bool>(&mut self) -> Result<(), VMError> {
+        if self.pc >= self.code.len() {
+            return Err(VMError::Terminated);
+        }
+        loop {
+            if let Err(err) = self.step_inline::<LOOP_STACK>() {
+                if err == VMError::Terminated {
+                    return Ok(());
+                } else {
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn run_for_inline<const LOOP_STACK: bool>(&mut self, steps: u64) -> Result<RunStatus, VMError> {
+        if self.pc >= self.code.len() {
+            return Err(VMError::Terminated);
+        }
+        for _ in 0..steps {
+            match self.step_inline::<LOOP_STACK>() {
+                Ok(()) => {}
+                Err(VMError::Terminated) => return Ok(RunStatus::Finished),
+                Err(err) => return Err(err),
+            }
+        }
+        if self.pc >= self.code.len() {
+            Ok(RunStatus::Finished)
+        } else {
+            Ok(RunStatus::OutOfFuel)
+        }
+    }
+
+    /// Executes one instruction. With `LOOP_STACK`, loops are tracked on the
+    /// loop stack, instead of jumping to their statically resolved targets.
+    #[inline(always)]
+    fn step_inline<const LOOP_STACK: bool>(&mut self) -> Result<(), VMError> {
+        if let Some(op) = self.code.get(self.pc) {
+            match *op {
+                Op::MoveLeft => {
+                    self.success = self.tree.move_left();
+                }
+                Op::MoveRight => {
+                    self.success = self.tree.move_right();
+                }
+                Op::MoveUp => {
+                    self.success = self.tree.move_up();
+                }
+                Op::PushRoot => {
+                    self.tree.push_root();
+                }
+                Op::PopRoot => {
+                    self.success = if self.semantics.pop_last_root {
+                        self.tree.pop_last_root().is_some()
+                    } else {
+                        self.tree.pop_root().is_some()
+                    };
+                }
+                Op::LoopHead(tail) => {
+                    if LOOP_STACK {
+                        self.loop_stack.push((self.pc, tail));
+                    }
+                }
+                Op::LoopTail(head) => {
+                    if LOOP_STACK {
+                        let Some(&(head, _)) = self.loop_stack.last() else {
+                            return Err(VMError::LoopStackUnderflow);
+                        };
+                        if self.success {
+                            self.pc = head;
+                        } else {
+                            self.loop_stack.pop();
+                        }
+                    } else if self.success {
+                        self.pc = head;
+                    }
+                    self.success = true;
+                }
+                Op::NewLeft => {
+                    self.tree.new_left()?;
+                    self.success = true;
+                }
+                Op::NewRight => {
+                    self.tree.new_right()?;
+                    self.success = true;
+                }
+                Op::Delete => {
+                    self.delete()?;
+                }
+                Op::Break(tail) => {
+                    self.success = self.tree.at_root();
+                    if self.success {
+                        self.pc = if LOOP_STACK {
+                            let top = self.loop_stack.last();
+                            top.map_or(self.code.len(), |&(_, tail)| tail)
+                        } else {
+                            tail
+                        };
+                        if self.semantics.break_restarts && self.pc == self.code.len() {
+                            self.pc = 0;
+                            return Ok(());
+                        }
+                    }
+                }
+                Op::MoveUpToRoot => {
+                    while self.tree.move_up() {}
+                    self.success = true;
+                }
+                Op::MoveLeftToEnd => {
+                    while self.tree.move_left() {}
+                    self.success = true;
+                }
+                Op::MoveRightToEnd => {
+                    while self.tree.move_right() {}
+                    self.success = true;
+                }
+                Op::RepeatMoveUp(n) => {
+                    for _ in 0..n {
+                        self.success = self.tree.move_up();
+                        if !self.success {
+                            break;
+                        }
+                    }
+                }
+                Op::NewLeftChain(n) => {
+                    self.tree.new_left_chain(n)?;
+                    self.success = true;
+                }
+                Op::NewRightChain(n) => {
+                    self.tree.new_right_chain(n)?;
+                    self.success = true;
+                }
+                Op::DeleteLeft => {
+                    self.tree.new_left_chain(1)?;
+                    self.delete()?;
+                }
+                Op::DeleteRight => {
+                    self.tree.new_right_chain(1)?;
+                    self.delete()?;
+                }
+            }
+            self.pc += 1;
+            Ok(())
+        } else {
+            Err(VMError::Terminated)
+        }
+    }
+
+    #[inline(always)]
+    fn delete(&mut self) -> Result<(), VMError> {
+        if self.semantics.delete_root && self.tree.at_root() {
+            if !self.tree.detach() {
+                return Err(VMError::DeletedTreeRoot);
+            }
+            self.success = true;
+        } else {
+            self.success = self.tree.delete();
+        }
+        Ok(())
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.prog
+    }
+
+    /// Returns the index of the next instruction to execute. For a VM from
+    /// [`VM::optimized`], this indexes the optimized code.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn tree(&self) -> &RootedTree {
+        &self.tree
+    }
+
+    pub fn semantics(&self) -> Semantics {
+        self.semantics
+    }
+
+    /// Limits the number of live nodes in the tree, after which creating a
+    /// node errors with [`VMError::OutOfMemory`]. Deleted nodes do not count,
+    /// but subtrees detached by `-` with [`Semantics::delete_root`] do. See
+    /// [`MultiTree::set_max_nodes`](crate::tree::MultiTree::set_max_nodes).
+    pub fn set_max_nodes(&mut self, max_nodes: Option<usize>) {
+        self.tree.set_max_nodes(max_nodes);
+    }
+}
+
+impl Semantics {
+    /// The semantics of Leafy and leaf.leaf.
+    pub const LEAFY: Semantics = Semantics {
+        pop_last_root: false,
+        delete_root: false,
+        break_keeps_loop: false,
+        break_restarts: false,
+    };
+
+    /// The semantics of the reference implementation.
+    pub const REFERENCE: Semantics = Semantics {
+        pop_last_root: true,
+        delete_root: true,
+        break_keeps_loop: true,
+        break_restarts: true,
+    };
+}
+
+impl Default for Semantics {
+    fn default() -> Self {
+        Semantics::LEAFY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEMANTICS: [Semantics; 2] = [Semantics::LEAFY, Semantics::REFERENCE];
+
+    /// Executes the instruction at `pc` on a new VM, which has a single root
+    /// and an empty loop stack.
+    fn step_at(src: &str, pc: usize, semantics: Semantics) -> (VM, Result<(), VMError>) {
+        let mut vm = VM::with_semantics(Program::parse(src).unwrap(), semantics);
+        vm.pc = pc;
+        let res = vm.step();
+        (vm, res)
+    }
+
+    /// Like `step_at`, but after `}` has popped the last root with
+    /// [`Semantics::REFERENCE`], so the root stack is empty.
+    fn step_rootless(src: &str) -> (VM, Result<(), VMError>) {
+        let mut vm = VM::with_semantics(Program::parse(src).unwrap(), Semantics::REFERENCE);
+        vm.step().unwrap();
+        assert!(vm.success && vm.tree.root_stack().is_empty());
+        vm.success = false;
+        let res = vm.step();
+        (vm, res)
+    }
+
+    #[test]
+    fn move_at_root() {
+        for semantics in SEMANTICS {
+            for src in ["<", ">", "^"] {
+                let (vm, res) = step_at(src, 0, semantics);
+                assert_eq!(res, Ok(()), "{src}");
+                assert!(!vm.success, "{src}");
+                assert_eq!(vm.tree.cursor(), vm.tree.root(), "{src}");
+            }
+        }
+        for src in ["}<", "}>", "}^"] {
+            let (vm, res) = step_rootless(src);
+            assert_eq!(res, Ok(()), "{src}");
+            assert!(!vm.success, "{src}");
+        }
+    }
+
+    #[test]
+    fn push_root() {
+        for semantics in SEMANTICS {
+            let (vm, res) = step_at("{", 0, semantics);
+            assert_eq!(res, Ok(()));
+            assert_eq!(vm.tree.root_stack(), [vm.tree.root(); 2]);
+        }
+        let (vm, res) = step_rootless("}{");
+        assert_eq!(res, Ok(()));
+        assert_eq!(vm.tree.root_stack(), [vm.tree.root()]);
+    }
+
+    #[test]
+    fn pop_last_root() {
+        let (vm, res) = step_at("}", 0, Semantics::LEAFY);
+        assert_eq!(res, Ok(()));
+        assert!(!vm.success);
+        assert_eq!(vm.tree.root_stack(), [vm.tree.root()]);
+        let (vm, res) = step_at("}", 0, Semantics::REFERENCE);
+        assert_eq!(res, Ok(()));
+        assert!(vm.success);
+        assert_eq!(vm.tree.root_stack(), []);
+        let (vm, res) = step_rootless("}}");
+        assert_eq!(res, Ok(()));
+        assert!(!vm.success);
+    }
+
+    #[test]
+    fn loop_head() {
+        for semantics in SEMANTICS {
+            let (vm, res) = step_at("()", 0, semantics);
+            assert_eq!(res, Ok(()));
+            assert_eq!(vm.pc, 1);
+            let loop_stack: &[_] = if semantics.break_keeps_loop {
+                &[(0, 1)]
+            } else {
+                &[]
+            };
+            assert_eq!(vm.loop_stack, loop_stack);
+        }
+    }
+
+    #[test]
+    fn loop_tail_underflow() {
+        let (vm, res) = step_at("()", 1, Semantics::LEAFY);
+        assert_eq!(res, Ok(()));
+        assert_eq!(vm.pc, 2);
+        assert!(vm.success);
+        let (vm, res) = step_at("()", 1, Semantics::REFERENCE);
+        assert_eq!(res, Err(VMError::LoopStackUnderflow));
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn new_child_at_root() {
+        for semantics in SEMANTICS {
+            for src in ["+", "*"] {
+                let (vm, res) = step_at(src, 0, semantics);
+                assert_eq!(res, Ok(()), "{src}");
+                assert!(vm.success, "{src}");
+                let root = &vm.tree.unrooted()[vm.tree.root()];
+                assert_eq!(root.left().is_some(), src == "+");
+                assert_eq!(root.right().is_some(), src == "*");
+            }
+        }
+    }
+
+    #[test]
+    fn delete_at_root() {
+        let (vm, res) = step_at("-", 0, Semantics::LEAFY);
+        assert_eq!(res, Ok(()));
+        assert!(!vm.success);
+        let (vm, res) = step_at("-", 0, Semantics::REFERENCE);
+        assert_eq!(res, Err(VMError::DeletedTreeRoot));
+        assert_eq!(vm.pc, 0);
+        let (vm, res) = step_rootless("}-");
+        assert_eq!(res, Ok(()));
+        assert!(!vm.success);
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        let (mut vm, res) = step_at("?", 0, Semantics::LEAFY);
+        assert_eq!(res, Ok(()));
+        assert!(vm.success);
+        assert_eq!(vm.step(), Err(VMError::Terminated));
+        let (vm, res) = step_at("?", 0, Semantics::REFERENCE);
+        assert_eq!(res, Ok(()));
+        assert!(vm.success);
+        assert_eq!(vm.pc, 0);
+        let (vm, res) = step_rootless("}?");
+        assert_eq!(res, Ok(()));
+        assert!(!vm.success);
+        assert_eq!(vm.pc, 2);
+    }
+
+    #[test]
+    fn break_with_empty_loop_stack() {
+        // The `?` is in a loop, but the loop stack does not have it.
+        let (mut vm, res) = step_at("(?)", 1, Semantics::LEAFY);
+        assert_eq!(res, Ok(()));
+        assert_eq!(vm.step(), Err(VMError::Terminated));
+        let (vm, res) = step_at("(?)", 1, Semantics::REFERENCE);
+        assert_eq!(res, Ok(()));
+        assert_eq!(vm.pc, 0);
+    }
+
+    #[test]
+    fn run_for_exact_steps() {
+        // `+<` 5 times, then `(^)` climbs back up in 11 steps.
+        let prog = Program::parse("+<+<+<+<+<(^)").unwrap();
+        for semantics in SEMANTICS {
+            let mut vm = VM::with_semantics(prog.clone(), semantics);
+            assert_eq!(vm.run_for(0), Ok(RunStatus::OutOfFuel));
+            assert_eq!(vm.pc, 0);
+            assert_eq!(vm.run_for(3), Ok(RunStatus::OutOfFuel));
+            assert_eq!(vm.pc, 3);
+            assert_eq!(vm.run_for(7), Ok(RunStatus::OutOfFuel));
+            assert_eq!(vm.pc, 10);
+            // The loop runs `(^)` 5 times and `^)` once more when it fails.
+            assert_eq!(vm.run_for(12), Ok(RunStatus::OutOfFuel));
+            assert_eq!(vm.pc, 12);
+            assert_eq!(vm.run_for(1), Ok(RunStatus::Finished));
+            assert_eq!(vm.tree.cursor(), vm.tree.root());
+            assert_eq!(vm.run_for(1), Err(VMError::Terminated));
+        }
+    }
+
+    #[test]
+    fn run_for_resumes() {
+        let prog = Program::parse("+<+<*>(^)<{<*>-(^)}(^)").unwrap();
+        for semantics in SEMANTICS {
+            let mut whole = VM::with_semantics(prog.clone(), semantics);
+            whole.run().unwrap();
+            for steps in [1, 2, 7, 100] {
+                let mut parts = VM::with_semantics(prog.clone(), semantics);
+                while parts.run_for(steps) == Ok(RunStatus::OutOfFuel) {}
+                assert_eq!(parts.pc, whole.pc);
+                assert_eq!(parts.success, whole.success);
+                assert_eq!(
+                    parts.tree.dump_dot_to_string(),
+                    whole.tree.dump_dot_to_string()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn run_for_infinite_loop() {
+        let mut vm = VM::new(Program::parse("+(<+)").unwrap());
+        assert_eq!(vm.run_for(1000), Ok(RunStatus::OutOfFuel));
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.tree.unrooted().allocated(), 1 + 1 + 333);
+    }
+
+    #[test]
+    fn max_nodes() {
+        for semantics in SEMANTICS {
+            let mut vm = VM::with_semantics(Program::parse("+(<+)").unwrap(), semantics);
+            vm.set_max_nodes(Some(100));
+            assert_eq!(vm.run(), Err(VMError::OutOfMemory(OutOfMemory)));
+            // It stops at the `+` that failed, with 100 nodes.
+            assert_eq!(vm.pc, 3);
+            assert_eq!(vm.tree.unrooted().allocated(), 100);
+            assert_eq!(vm.run_for(10), Err(VMError::OutOfMemory(OutOfMemory)));
+        }
+    }
+
+    #[test]
+    fn max_nodes_live() {
+        // Each iteration creates a node and deletes it, so there are never
+        // more than 2 live nodes.
+        let prog = Program::parse("(+<-)").unwrap();
+        for semantics in SEMANTICS {
+            let mut vm = VM::with_semantics(prog.clone(), semantics);
+            vm.set_max_nodes(Some(2));
+            assert_eq!(vm.run_for(100_000), Ok(RunStatus::OutOfFuel));
+        }
+    }
+
+    #[test]
+    fn max_nodes_superinstructions() {
+        // `-` frees the first chain, which the second reuses, but the third
+        // needs two more nodes than the limit allows.
+        let prog = Program::parse("+<+<+<^^-+<+<+<*>*>").unwrap();
+        for semantics in SEMANTICS {
+            let mut vm = VM::optimized(prog.clone(), semantics);
+            assert_eq!(
+                vm.code.ops(),
+                [
+                    Op::NewLeftChain(3),
+                    Op::RepeatMoveUp(2),
+                    Op::Delete,
+                    Op::NewLeftChain(3),
+                    Op::NewRightChain(2),
+                ],
+            );
+            vm.set_max_nodes(Some(4));
+            for _ in 0..4 {
+                vm.step().unwrap();
+            }
+            let tree = vm.tree.clone();
+            // The chain fails as a whole, without changing the tree.
+            assert_eq!(vm.step(), Err(VMError::OutOfMemory(OutOfMemory)));
+            assert_eq!(vm.pc, 4);
+            assert_eq!(vm.tree.diff(&tree), None);
+            assert_eq!(vm.tree.unrooted().allocated(), 4);
+        }
+    }
+}
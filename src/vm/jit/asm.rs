@@ -0,0 +1,356 @@
+//! A minimal x86-64 assembler for the instructions used by the JIT.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+    R15 = 15,
+}
+
+/// A memory operand, `[base + index * scale + disp]`.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Mem {
+    base: Reg,
+    index: Option<(Reg, u8)>,
+    disp: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Cond {
+    B = 0x2,
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    Be = 0x6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Label(usize);
+
+/// An operand in the r/m field of a ModRM byte.
+#[derive(Clone, Copy, Debug)]
+enum Rm {
+    Reg(Reg),
+    Mem(Mem),
+}
+
+/// Two-operand ALU instructions, with their opcode in the `r/m, reg` form and
+/// their extension in the immediate form.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Alu {
+    Add,
+    Sub,
+    Xor,
+    Cmp,
+}
+
+pub(super) struct Asm {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// The offsets of `rel32` operands and the labels they refer to.
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Mem {
+    pub(super) fn base(base: Reg, disp: i32) -> Self {
+        Mem {
+            base,
+            index: None,
+            disp,
+        }
+    }
+
+    pub(super) fn index(base: Reg, index: Reg, scale: u8, disp: i32) -> Self {
+        debug_assert!(index != Reg::Rsp && matches!(scale, 1 | 2 | 4 | 8));
+        Mem {
+            base,
+            index: Some((index, scale)),
+            disp,
+        }
+    }
+}
+
+impl Alu {
+    fn opcode(self) -> u8 {
+        match self {
+            Alu::Add => 0x01,
+            Alu::Sub => 0x29,
+            Alu::Xor => 0x31,
+            Alu::Cmp => 0x39,
+        }
+    }
+
+    fn ext(self) -> u8 {
+        match self {
+            Alu::Add => 0,
+            Alu::Sub => 5,
+            Alu::Xor => 6,
+            Alu::Cmp => 7,
+        }
+    }
+}
+
+impl Asm {
+    pub(super) fn new() -> Self {
+        Asm {
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    pub(super) fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub(super) fn bind(&mut self, label: Label) {
+        debug_assert!(self.labels[label.0].is_none());
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// Returns the offset of a bound label.
+    pub(super) fn offset(&self, label: Label) -> usize {
+        self.labels[label.0].expect("label is unbound")
+    }
+
+    /// Resolves the jumps to labels and returns the code.
+    pub(super) fn finish(mut self) -> Vec<u8> {
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label.0].expect("label is unbound");
+            let rel = target as i64 - (at as i64 + 4);
+            let rel = i32::try_from(rel).expect("jump out of range");
+            self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        self.code
+    }
+
+    /// Emits an instruction with a ModRM operand, preceded by a REX prefix
+    /// when needed. `reg` is the register or opcode extension in the reg
+    /// field. With `byte_reg`, a REX prefix is always emitted, so that the
+    /// low byte registers are addressed.
+    fn op(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: Rm, byte_reg: bool) {
+        let (rm_bits, index_bits) = match rm {
+            Rm::Reg(r) => (r as u8, 0),
+            Rm::Mem(mem) => (mem.base as u8, mem.index.map_or(0, |(i, _)| i as u8)),
+        };
+        let rex =
+            0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index_bits >> 3) << 1 | (rm_bits >> 3);
+        if rex != 0x40 || byte_reg {
+            self.code.push(rex);
+        }
+        self.code.extend_from_slice(opcode);
+        let reg = (reg & 7) << 3;
+        match rm {
+            Rm::Reg(r) => self.code.push(0xc0 | reg | (r as u8 & 7)),
+            Rm::Mem(mem) => {
+                let base = mem.base as u8 & 7;
+                let mode = if mem.disp == 0 && base != 5 {
+                    0x00
+                } else if i8::try_from(mem.disp).is_ok() {
+                    0x40
+                } else {
+                    0x80
+                };
+                if mem.index.is_some() || base == 4 {
+                    self.code.push(mode | reg | 4);
+                    let (index, scale) = mem.index.map_or((4, 0), |(index, scale)| {
+                        (index as u8 & 7, scale.trailing_zeros() as u8)
+                    });
+                    self.code.push(scale << 6 | index << 3 | base);
+                } else {
+                    self.code.push(mode | reg | base);
+                }
+                match mode {
+                    0x40 => self.code.push(mem.disp as u8),
+                    0x80 => self.code.extend_from_slice(&mem.disp.to_le_bytes()),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub(super) fn push(&mut self, r: Reg) {
+        if r as u8 >= 8 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x50 | (r as u8 & 7));
+    }
+
+    pub(super) fn pop(&mut self, r: Reg) {
+        if r as u8 >= 8 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x58 | (r as u8 & 7));
+    }
+
+    pub(super) fn ret(&mut self) {
+        self.code.push(0xc3);
+    }
+
+    /// `mov dst, src`, of 32 bits, zero-extending.
+    pub(super) fn mov32(&mut self, dst: Reg, src: Reg) {
+        self.op(false, &[0x89], src as u8, Rm::Reg(dst), false);
+    }
+
+    pub(super) fn mov64(&mut self, dst: Reg, src: Reg) {
+        self.op(true, &[0x89], src as u8, Rm::Reg(dst), false);
+    }
+
+    /// Loads 32 bits, zero-extending.
+    pub(super) fn load32(&mut self, dst: Reg, src: Mem) {
+        self.op(false, &[0x8b], dst as u8, Rm::Mem(src), false);
+    }
+
+    pub(super) fn load64(&mut self, dst: Reg, src: Mem) {
+        self.op(true, &[0x8b], dst as u8, Rm::Mem(src), false);
+    }
+
+    pub(super) fn store32(&mut self, dst: Mem, src: Reg) {
+        self.op(false, &[0x89], src as u8, Rm::Mem(dst), false);
+    }
+
+    pub(super) fn store64(&mut self, dst: Mem, src: Reg) {
+        self.op(true, &[0x89], src as u8, Rm::Mem(dst), false);
+    }
+
+    /// Stores an immediate of 32 bits.
+    pub(super) fn store_imm32(&mut self, dst: Mem, imm: u32) {
+        self.op(false, &[0xc7], 0, Rm::Mem(dst), false);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// Stores an immediate of 32 bits, sign-extended to 64.
+    pub(super) fn store_imm64(&mut self, dst: Mem, imm: i32) {
+        self.op(true, &[0xc7], 0, Rm::Mem(dst), false);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// Loads an immediate, with the shortest encoding.
+    pub(super) fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        let r = dst as u8;
+        if let Ok(imm) = u32::try_from(imm) {
+            if r >= 8 {
+                self.code.push(0x41);
+            }
+            self.code.push(0xb8 | (r & 7));
+            self.code.extend_from_slice(&imm.to_le_bytes());
+        } else {
+            self.code.push(0x48 | (r >> 3));
+            self.code.push(0xb8 | (r & 7));
+            self.code.extend_from_slice(&imm.to_le_bytes());
+        }
+    }
+
+    pub(super) fn lea(&mut self, dst: Reg, src: Mem) {
+        self.op(true, &[0x8d], dst as u8, Rm::Mem(src), false);
+    }
+
+    /// `op dst, src`, of 32 bits.
+    pub(super) fn alu32(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.op(false, &[op.opcode()], src as u8, Rm::Reg(dst), false);
+    }
+
+    /// `op [dst], src`, of 32 bits.
+    pub(super) fn alu32_mem(&mut self, op: Alu, dst: Mem, src: Reg) {
+        self.op(false, &[op.opcode()], src as u8, Rm::Mem(dst), false);
+    }
+
+    /// `op dst, [src]`, of 64 bits.
+    pub(super) fn alu64_load(&mut self, op: Alu, dst: Reg, src: Mem) {
+        self.op(true, &[op.opcode() + 2], dst as u8, Rm::Mem(src), false);
+    }
+
+    /// `op dst, imm`, of 64 bits.
+    pub(super) fn alu64_imm(&mut self, op: Alu, dst: Reg, imm: i32) {
+        self.alu_imm(true, op, Rm::Reg(dst), imm);
+    }
+
+    /// `op qword [dst], imm`.
+    pub(super) fn alu64_mem_imm(&mut self, op: Alu, dst: Mem, imm: i32) {
+        self.alu_imm(true, op, Rm::Mem(dst), imm);
+    }
+
+    fn alu_imm(&mut self, wide: bool, op: Alu, dst: Rm, imm: i32) {
+        if let Ok(imm) = i8::try_from(imm) {
+            self.op(wide, &[0x83], op.ext(), dst, false);
+            self.code.push(imm as u8);
+        } else {
+            self.op(wide, &[0x81], op.ext(), dst, false);
+            self.code.extend_from_slice(&imm.to_le_bytes());
+        }
+    }
+
+    /// `test a, b`, of 32 bits.
+    pub(super) fn test32(&mut self, a: Reg, b: Reg) {
+        self.op(false, &[0x85], b as u8, Rm::Reg(a), false);
+    }
+
+    /// `test a, b`, of 64 bits.
+    pub(super) fn test64(&mut self, a: Reg, b: Reg) {
+        self.op(true, &[0x85], b as u8, Rm::Reg(a), false);
+    }
+
+    pub(super) fn inc64(&mut self, r: Reg) {
+        self.op(true, &[0xff], 0, Rm::Reg(r), false);
+    }
+
+    pub(super) fn dec64(&mut self, r: Reg) {
+        self.op(true, &[0xff], 1, Rm::Reg(r), false);
+    }
+
+    /// Sets the low byte of a register to the condition.
+    pub(super) fn setcc(&mut self, cond: Cond, dst: Reg) {
+        self.op(false, &[0x0f, 0x90 | cond as u8], 0, Rm::Reg(dst), true);
+    }
+
+    /// `cmovcc dst, src`, of 32 bits.
+    pub(super) fn cmov32(&mut self, cond: Cond, dst: Reg, src: Reg) {
+        self.op(
+            false,
+            &[0x0f, 0x40 | cond as u8],
+            dst as u8,
+            Rm::Reg(src),
+            false,
+        );
+    }
+
+    pub(super) fn jmp(&mut self, label: Label) {
+        self.code.push(0xe9);
+        self.rel32(label);
+    }
+
+    pub(super) fn jcc(&mut self, cond: Cond, label: Label) {
+        self.code.extend_from_slice(&[0x0f, 0x80 | cond as u8]);
+        self.rel32(label);
+    }
+
+    /// Jumps to the address stored in memory.
+    pub(super) fn jmp_mem(&mut self, target: Mem) {
+        self.op(false, &[0xff], 4, Rm::Mem(target), false);
+    }
+
+    /// Jumps to the address in a register.
+    pub(super) fn jmp_reg(&mut self, target: Reg) {
+        self.op(false, &[0xff], 4, Rm::Reg(target), false);
+    }
+
+    /// Calls the address in a register.
+    pub(super) fn call_reg(&mut self, target: Reg) {
+        self.op(false, &[0xff], 2, Rm::Reg(target), false);
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+}
@@ -0,0 +1,853 @@
+use std::mem::{self, offset_of};
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::{fmt, io};
+
+use super::asm::{Alu, Asm, Cond, Label, Mem, Reg, Reg::*};
+use super::exec::ExecMemory;
+use crate::tree::{Node, NodeId};
+use crate::{Code, Op, RunStatus, Semantics, VMError, VM};
+
+/// Compiled code translated to x86-64 machine code, which operates directly on the
+/// node arena and root stack of the tree.
+///
+/// While running, the code keeps its state in callee-saved registers:
+///
+/// - `rbx`: the cursor
+/// - `rbp`: the remaining steps
+/// - `r12`: the address of the arena, less one node, so that a node is at
+///   `r12 + id * 12`
+/// - `r13`: the [`Ctx`]
+/// - `r14`: the success flag
+/// - `r15`: the top root, or 0 when the root stack is empty
+///
+/// Moves, creating nodes from the free list or within the capacity of the
+/// arena, deleting, and pushing and popping roots are inlined, and the rest
+/// calls into the runtime. The runtime returns failures as values, which the
+/// code turns into exit statuses, since it cannot unwind.
+#[derive(Clone)]
+pub(crate) struct Jit {
+    /// The machine code, which only refers to itself relatively and to the
+    /// runtime absolutely, so clones share it.
+    code: Arc<ExecMemory>,
+    /// The offset of the code for each op, followed by those for the two
+    /// exits past the end of the code.
+    offsets: Vec<usize>,
+    /// The addresses of `offsets`, for jumping to a dynamic target.
+    labels: Vec<usize>,
+}
+
+/// The state shared between the compiled code and the runtime. The arena and
+/// root stack are lent to the code as raw parts and given back to the VM
+/// whenever the runtime is called and when the code exits.
+#[repr(C)]
+struct Ctx {
+    /// The address of the arena, less one node.
+    nodes: *mut u8,
+    len: usize,
+    /// The number of nodes that fit without growing the arena, capped by the
+    /// limit on nodes.
+    limit: usize,
+    free: u32,
+    cursor: u32,
+    roots: *mut NodeId,
+    roots_len: usize,
+    roots_cap: usize,
+    top: u32,
+    success: u32,
+    pc: usize,
+    labels: *const usize,
+    vm: *mut VM,
+}
+
+/// The signature of the entry point, called with the context, the address to
+/// start at, and the number of steps to run for. It returns the exit status.
+type Entry = unsafe extern "sysv64" fn(*mut Ctx, usize, u64) -> u32;
+
+const FINISHED: u32 = 0;
+const OUT_OF_FUEL: u32 = 1;
+const OUT_OF_MEMORY: u32 = 2;
+const DELETED_TREE_ROOT: u32 = 3;
+const LOOP_STACK_UNDERFLOW: u32 = 4;
+
+const LEFT: i32 = offset_of!(Node, left) as i32;
+const RIGHT: i32 = offset_of!(Node, right) as i32;
+const PARENT: i32 = offset_of!(Node, parent) as i32;
+
+struct Compiler {
+    asm: Asm,
+    semantics: Semantics,
+    /// The label of each op and of the two exits past the end.
+    insts: Vec<Label>,
+    exit: Label,
+    /// Exits out of the way of the code, with a status and a pc.
+    stubs: Vec<(Label, u32, usize)>,
+}
+
+impl Jit {
+    /// Compiles code and maps it. Fails when the operating system refuses the
+    /// mapping.
+    pub(crate) fn compile(code: &Code, semantics: Semantics) -> io::Result<Self> {
+        let mut asm = Asm::new();
+        let insts = (0..code.len() + 2).map(|_| asm.new_label()).collect();
+        let exit = asm.new_label();
+        let mut c = Compiler {
+            asm,
+            semantics,
+            insts,
+            exit,
+            stubs: Vec::new(),
+        };
+        c.prologue();
+        for (pc, &op) in code.ops().iter().enumerate() {
+            c.op(pc, op);
+        }
+        for pc in code.len()..code.len() + 2 {
+            c.asm.bind(c.insts[pc]);
+            c.exit(FINISHED, pc);
+        }
+        for (label, status, pc) in mem::take(&mut c.stubs) {
+            c.asm.bind(label);
+            c.exit(status, pc);
+        }
+        c.epilogue();
+
+        let offsets = c
+            .insts
+            .iter()
+            .map(|&label| c.asm.offset(label))
+            .collect::<Vec<_>>();
+        let mem = ExecMemory::new(&c.asm.finish())?;
+        let base = mem.as_ptr() as usize;
+        let labels = offsets.iter().map(|offset| base + offset).collect();
+        Ok(Jit {
+            code: Arc::new(mem),
+            offsets,
+            labels,
+        })
+    }
+
+    pub(crate) fn run(&self, vm: &mut VM) -> Result<(), VMError> {
+        if vm.pc >= vm.code.len() {
+            return Err(VMError::Terminated);
+        }
+        // Running for the maximum number of steps takes centuries, but is
+        // resumed to be unbounded.
+        while self.run_for(vm, u64::MAX)? == RunStatus::OutOfFuel {}
+        Ok(())
+    }
+
+    pub(crate) fn run_for(&self, vm: &mut VM, steps: u64) -> Result<RunStatus, VMError> {
+        if vm.pc >= vm.code.len() {
+            return Err(VMError::Terminated);
+        }
+        let mut ctx = unsafe { Ctx::new(vm, &self.labels) };
+        let status = unsafe {
+            let entry = mem::transmute::<*const u8, Entry>(self.code.as_ptr());
+            let status = entry(&mut ctx, self.labels[vm.pc], steps);
+            ctx.store();
+            status
+        };
+        match status {
+            FINISHED => Ok(RunStatus::Finished),
+            OUT_OF_FUEL => Ok(RunStatus::OutOfFuel),
+            OUT_OF_MEMORY => Err(crate::tree::OutOfMemory.into()),
+            DELETED_TREE_ROOT => Err(VMError::DeletedTreeRoot),
+            LOOP_STACK_UNDERFLOW => Err(VMError::LoopStackUnderflow),
+            _ => unreachable!("invalid exit status {status}"),
+        }
+    }
+
+    pub(crate) fn step(&self, vm: &mut VM) -> Result<(), VMError> {
+        self.run_for(vm, 1).map(|_| ())
+    }
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Jit")
+            .field("code", &self.code.as_ptr())
+            .field("offsets", &self.offsets)
+            .finish()
+    }
+}
+
+impl Compiler {
+    fn prologue(&mut self) {
+        for r in [Rbx, Rbp, R12, R13, R14, R15] {
+            self.asm.push(r);
+        }
+        // Align the stack for calls into the runtime. The slot is used to
+        // save `rcx` across them.
+        self.asm.alu64_imm(Alu::Sub, Rsp, 8);
+        self.asm.mov64(R13, Rdi);
+        self.asm.mov64(Rbp, Rdx);
+        self.asm.load32(Rbx, ctx(offset_of!(Ctx, cursor)));
+        self.asm.load32(R14, ctx(offset_of!(Ctx, success)));
+        self.asm.load64(R12, ctx(offset_of!(Ctx, nodes)));
+        self.asm.load32(R15, ctx(offset_of!(Ctx, top)));
+        self.asm.jmp_reg(Rsi);
+    }
+
+    /// Exits with the status in `eax` and the pc in `rcx`.
+    fn epilogue(&mut self) {
+        self.asm.bind(self.exit);
+        self.asm.store64(ctx(offset_of!(Ctx, pc)), Rcx);
+        self.asm.store32(ctx(offset_of!(Ctx, cursor)), Rbx);
+        self.asm.store32(ctx(offset_of!(Ctx, success)), R14);
+        self.asm.alu64_imm(Alu::Add, Rsp, 8);
+        for r in [R15, R14, R13, R12, Rbp, Rbx] {
+            self.asm.pop(r);
+        }
+        self.asm.ret();
+    }
+
+    fn exit(&mut self, status: u32, pc: usize) {
+        self.asm.mov_imm(Rcx, pc as u64);
+        self.asm.mov_imm(Rax, status as u64);
+        self.asm.jmp(self.exit);
+    }
+
+    /// Returns a label that exits with a status at an op.
+    fn stub(&mut self, status: u32, pc: usize) -> Label {
+        let label = self.asm.new_label();
+        self.stubs.push((label, status, pc));
+        label
+    }
+
+    fn op(&mut self, pc: usize, op: Op) {
+        self.asm.bind(self.insts[pc]);
+        let out_of_fuel = self.stub(OUT_OF_FUEL, pc);
+        self.asm.alu64_imm(Alu::Sub, Rbp, 1);
+        self.asm.jcc(Cond::B, out_of_fuel);
+        let next = self.asm.new_label();
+        match op {
+            Op::MoveLeft => self.move_down(LEFT),
+            Op::MoveRight => self.move_down(RIGHT),
+            Op::MoveUp => {
+                let fail = self.asm.new_label();
+                self.parent(fail);
+                self.asm.mov32(Rbx, Rax);
+                self.set_success(true);
+                self.asm.jmp(next);
+                self.asm.bind(fail);
+                self.set_success(false);
+            }
+            Op::PushRoot => self.push_root(pc),
+            Op::PopRoot => self.pop_root(next),
+            Op::LoopHead(tail) => {
+                if self.semantics.break_keeps_loop {
+                    self.asm.mov_imm(Rsi, pc as u64);
+                    self.asm.mov_imm(Rdx, tail as u64);
+                    self.call(loop_head as *const ());
+                    let out_of_memory = self.stub(OUT_OF_MEMORY, pc);
+                    self.asm.test32(Rax, Rax);
+                    self.asm.jcc(Cond::E, out_of_memory);
+                }
+            }
+            Op::LoopTail(head) => {
+                if self.semantics.break_keeps_loop {
+                    self.asm.mov32(Rsi, R14);
+                    self.call(loop_tail as *const ());
+                    let underflow = self.stub(LOOP_STACK_UNDERFLOW, pc);
+                    self.asm.alu64_imm(Alu::Cmp, Rax, -1);
+                    self.asm.jcc(Cond::E, underflow);
+                    self.set_success(true);
+                    self.asm.test64(Rax, Rax);
+                    self.asm.jcc(Cond::E, next);
+                    self.jump_dynamic();
+                } else {
+                    self.asm.test32(R14, R14);
+                    self.set_success(true);
+                    self.asm.jcc(Cond::Ne, self.insts[head + 1]);
+                }
+            }
+            Op::NewLeft => self.new_child(LEFT, pc),
+            Op::NewRight => self.new_child(RIGHT, pc),
+            Op::Delete => self.delete(pc),
+            Op::Break(tail) => {
+                self.asm.alu32(Alu::Cmp, Rbx, R15);
+                self.asm.setcc(Cond::E, R14);
+                if self.semantics.break_keeps_loop {
+                    self.asm.jcc(Cond::Ne, next);
+                    self.call(break_target as *const ());
+                    self.jump_dynamic();
+                } else {
+                    let restart = self.semantics.break_restarts && tail == self.insts.len() - 2;
+                    let target = if restart { 0 } else { tail + 1 };
+                    self.asm.jcc(Cond::E, self.insts[target]);
+                }
+            }
+            Op::MoveUpToRoot => {
+                let (top, done) = (self.asm.new_label(), self.asm.new_label());
+                self.asm.bind(top);
+                self.parent(done);
+                self.asm.mov32(Rbx, Rax);
+                self.asm.jmp(top);
+                self.asm.bind(done);
+                self.set_success(true);
+            }
+            Op::MoveLeftToEnd => self.move_to_end(LEFT),
+            Op::MoveRightToEnd => self.move_to_end(RIGHT),
+            Op::RepeatMoveUp(n) => {
+                if n != 0 {
+                    let (top, fail) = (self.asm.new_label(), self.asm.new_label());
+                    self.asm.mov_imm(Rcx, n as u64);
+                    self.asm.bind(top);
+                    self.parent(fail);
+                    self.asm.mov32(Rbx, Rax);
+                    self.asm.dec64(Rcx);
+                    self.asm.jcc(Cond::Ne, top);
+                    self.set_success(true);
+                    self.asm.jmp(next);
+                    self.asm.bind(fail);
+                    self.set_success(false);
+                }
+            }
+            Op::NewLeftChain(n) => self.new_chain(LEFT, n, pc),
+            Op::NewRightChain(n) => self.new_chain(RIGHT, n, pc),
+            Op::DeleteLeft => {
+                self.new_child(LEFT, pc);
+                self.child(Rbx, LEFT);
+                self.delete(pc);
+            }
+            Op::DeleteRight => {
+                self.new_child(RIGHT, pc);
+                self.child(Rbx, RIGHT);
+                self.delete(pc);
+            }
+        }
+        self.asm.bind(next);
+    }
+
+    fn set_success(&mut self, success: bool) {
+        if success {
+            self.asm.mov_imm(R14, 1);
+        } else {
+            self.asm.alu32(Alu::Xor, R14, R14);
+        }
+    }
+
+    /// Computes the address of the node in a register.
+    fn node(&mut self, dst: Reg, id: Reg) {
+        self.asm.lea(dst, Mem::index(id, id, 2, 0));
+        self.asm.lea(dst, Mem::index(R12, dst, 4, 0));
+    }
+
+    /// Loads the child of the cursor on a side.
+    fn child(&mut self, dst: Reg, side: i32) {
+        self.asm.lea(Rax, Mem::index(Rbx, Rbx, 2, 0));
+        self.asm.load32(dst, Mem::index(R12, Rax, 4, side));
+    }
+
+    fn move_down(&mut self, side: i32) {
+        self.child(Rax, side);
+        self.asm.test32(Rax, Rax);
+        self.asm.setcc(Cond::Ne, R14);
+        self.asm.cmov32(Cond::Ne, Rbx, Rax);
+    }
+
+    fn move_to_end(&mut self, side: i32) {
+        let (top, done) = (self.asm.new_label(), self.asm.new_label());
+        self.asm.bind(top);
+        self.child(Rax, side);
+        self.asm.test32(Rax, Rax);
+        self.asm.jcc(Cond::E, done);
+        self.asm.mov32(Rbx, Rax);
+        self.asm.jmp(top);
+        self.asm.bind(done);
+        self.set_success(true);
+    }
+
+    /// Loads the parent of the cursor into `eax`, as in `RootedTree::parent`,
+    /// or jumps to `fail` when there is none.
+    fn parent(&mut self, fail: Label) {
+        if self.semantics.pop_last_root {
+            self.asm
+                .alu64_mem_imm(Alu::Cmp, ctx(offset_of!(Ctx, roots_len)), 0);
+            self.asm.jcc(Cond::E, fail);
+        }
+        self.asm.alu32(Alu::Cmp, Rbx, R15);
+        self.asm.jcc(Cond::E, fail);
+        self.asm.lea(Rax, Mem::index(Rbx, Rbx, 2, 0));
+        self.asm.load32(Rax, Mem::index(R12, Rax, 4, PARENT));
+        self.asm.test32(Rax, Rax);
+        self.asm.jcc(Cond::E, fail);
+    }
+
+    /// Replaces the child of the cursor on a side with a new node, as in
+    /// `MultiTree::new_left`, and sets the success flag. `rcx` is preserved.
+    fn new_child(&mut self, side: i32, pc: usize) {
+        let free = ctx(offset_of!(Ctx, free));
+        let (alloc, grow, slow, link, done) = (
+            self.asm.new_label(),
+            self.asm.new_label(),
+            self.asm.new_label(),
+            self.asm.new_label(),
+            self.asm.new_label(),
+        );
+        // Free the old child, so that it is reused.
+        self.child(Rax, side);
+        self.asm.test32(Rax, Rax);
+        self.asm.jcc(Cond::E, alloc);
+        self.asm.load32(Rsi, free);
+        self.asm.lea(Rdi, Mem::index(Rax, Rax, 2, 0));
+        self.asm.store32(Mem::index(R12, Rdi, 4, PARENT), Rsi);
+        self.asm.store32(free, Rax);
+        // Pop the free list, unless its head has children to free.
+        self.asm.bind(alloc);
+        self.asm.load32(Rax, free);
+        self.asm.test32(Rax, Rax);
+        self.asm.jcc(Cond::E, grow);
+        self.node(Rdi, Rax);
+        self.asm.alu64_mem_imm(Alu::Cmp, Mem::base(Rdi, LEFT), 0);
+        self.asm.jcc(Cond::Ne, slow);
+        self.asm.load32(Rsi, Mem::base(Rdi, PARENT));
+        self.asm.store32(free, Rsi);
+        self.asm.jmp(link);
+        // Otherwise, append to the arena within its capacity.
+        self.asm.bind(grow);
+        let len = ctx(offset_of!(Ctx, len));
+        self.asm.load64(Rax, len);
+        self.asm
+            .alu64_load(Alu::Cmp, Rax, ctx(offset_of!(Ctx, limit)));
+        self.asm.jcc(Cond::Ae, slow);
+        self.asm.inc64(Rax);
+        self.asm.store64(len, Rax);
+        self.node(Rdi, Rax);
+        self.asm.store_imm64(Mem::base(Rdi, LEFT), 0);
+        // Link it to the cursor.
+        self.asm.bind(link);
+        self.asm.store32(Mem::base(Rdi, PARENT), Rbx);
+        self.asm.lea(Rsi, Mem::index(Rbx, Rbx, 2, 0));
+        self.asm.store32(Mem::index(R12, Rsi, 4, side), Rax);
+        self.set_success(true);
+        self.asm.jmp(done);
+        // Otherwise, let the arena allocate it.
+        self.asm.bind(slow);
+        self.asm.store64(Mem::base(Rsp, 0), Rcx);
+        self.call(alloc_node as *const ());
+        self.asm.load64(Rcx, Mem::base(Rsp, 0));
+        self.asm.load64(R12, ctx(offset_of!(Ctx, nodes)));
+        let out_of_memory = self.stub(OUT_OF_MEMORY, pc);
+        self.asm.test32(Rax, Rax);
+        self.asm.jcc(Cond::E, out_of_memory);
+        self.node(Rdi, Rax);
+        self.asm.jmp(link);
+        self.asm.bind(done);
+    }
+
+    /// Creates a chain of `n` children on a side, as in
+    /// `RootedTree::new_left_chain`, after checking that the arena has room
+    /// for all of them, so that it fails without changing the tree.
+    fn new_chain(&mut self, side: i32, n: usize, pc: usize) {
+        if n == 0 {
+            return;
+        }
+        let (top, fits) = (self.asm.new_label(), self.asm.new_label());
+        // New ids within the capacity of the arena always fit.
+        self.asm.load64(Rax, ctx(offset_of!(Ctx, len)));
+        self.asm.alu64_imm(Alu::Add, Rax, n as i32);
+        self.asm
+            .alu64_load(Alu::Cmp, Rax, ctx(offset_of!(Ctx, limit)));
+        self.asm.jcc(Cond::Be, fits);
+        // Otherwise, let the arena count the nodes it can reuse.
+        self.asm.mov32(Rsi, Rbx);
+        self.asm.mov_imm(Rdx, n as u64);
+        self.asm.mov_imm(Rcx, (side == RIGHT) as u64);
+        self.call(has_capacity as *const ());
+        let out_of_memory = self.stub(OUT_OF_MEMORY, pc);
+        self.asm.test32(Rax, Rax);
+        self.asm.jcc(Cond::E, out_of_memory);
+        self.asm.bind(fits);
+        self.asm.mov_imm(Rcx, n as u64);
+        self.asm.bind(top);
+        self.new_child(side, pc);
+        self.child(Rbx, side);
+        self.asm.dec64(Rcx);
+        self.asm.jcc(Cond::Ne, top);
+    }
+
+    /// Deletes the node at the cursor, as in `VM::delete`.
+    fn delete(&mut self, pc: usize) {
+        let (normal, fail, done) = (
+            self.asm.new_label(),
+            self.asm.new_label(),
+            self.asm.new_label(),
+        );
+        if self.semantics.delete_root {
+            // Detach a root, without freeing it.
+            self.asm.alu32(Alu::Cmp, Rbx, R15);
+            self.asm.jcc(Cond::Ne, normal);
+            self.node(Rdx, Rbx);
+            self.asm.load32(Rcx, Mem::base(Rdx, PARENT));
+            let deleted_tree_root = self.stub(DELETED_TREE_ROOT, pc);
+            self.asm.test32(Rcx, Rcx);
+            self.asm.jcc(Cond::E, deleted_tree_root);
+            self.unlink();
+            self.asm.store_imm32(Mem::base(Rdx, PARENT), 0);
+            self.asm.mov32(Rbx, Rcx);
+            self.set_success(true);
+            self.asm.jmp(done);
+        }
+        self.asm.bind(normal);
+        self.parent(fail);
+        self.asm.mov32(Rcx, Rax);
+        self.node(Rdx, Rbx);
+        self.unlink();
+        let free = ctx(offset_of!(Ctx, free));
+        self.asm.load32(Rax, free);
+        self.asm.store32(Mem::base(Rdx, PARENT), Rax);
+        self.asm.store32(free, Rbx);
+        self.asm.mov32(Rbx, Rcx);
+        self.set_success(true);
+        self.asm.jmp(done);
+        self.asm.bind(fail);
+        self.set_success(false);
+        self.asm.bind(done);
+    }
+
+    /// Clears the link to the cursor from its parent in `ecx`.
+    fn unlink(&mut self) {
+        let (right, done) = (self.asm.new_label(), self.asm.new_label());
+        self.node(Rsi, Rcx);
+        self.asm.alu32_mem(Alu::Cmp, Mem::base(Rsi, LEFT), Rbx);
+        self.asm.jcc(Cond::Ne, right);
+        self.asm.store_imm32(Mem::base(Rsi, LEFT), 0);
+        self.asm.jmp(done);
+        self.asm.bind(right);
+        self.asm.alu32_mem(Alu::Cmp, Mem::base(Rsi, RIGHT), Rbx);
+        self.asm.jcc(Cond::Ne, done);
+        self.asm.store_imm32(Mem::base(Rsi, RIGHT), 0);
+        self.asm.bind(done);
+    }
+
+    fn push_root(&mut self, pc: usize) {
+        let (slow, done) = (self.asm.new_label(), self.asm.new_label());
+        let roots_len = ctx(offset_of!(Ctx, roots_len));
+        self.asm.load64(Rax, roots_len);
+        self.asm
+            .alu64_load(Alu::Cmp, Rax, ctx(offset_of!(Ctx, roots_cap)));
+        self.asm.jcc(Cond::Ae, slow);
+        self.asm.load64(Rdx, ctx(offset_of!(Ctx, roots)));
+        self.asm.store32(Mem::index(Rdx, Rax, 4, 0), Rbx);
+        self.asm.inc64(Rax);
+        self.asm.store64(roots_len, Rax);
+        self.asm.jmp(done);
+        self.asm.bind(slow);
+        self.asm.mov32(Rsi, Rbx);
+        self.call(push_root as *const ());
+        let out_of_memory = self.stub(OUT_OF_MEMORY, pc);
+        self.asm.test32(Rax, Rax);
+        self.asm.jcc(Cond::E, out_of_memory);
+        self.asm.bind(done);
+        self.asm.mov32(R15, Rbx);
+    }
+
+    fn pop_root(&mut self, next: Label) {
+        let (fail, popped) = (self.asm.new_label(), self.asm.new_label());
+        let roots_len = ctx(offset_of!(Ctx, roots_len));
+        let min = if self.semantics.pop_last_root { 0 } else { 1 };
+        self.asm.load64(Rax, roots_len);
+        self.asm.alu64_imm(Alu::Cmp, Rax, min);
+        self.asm.jcc(Cond::Be, fail);
+        self.asm.dec64(Rax);
+        self.asm.store64(roots_len, Rax);
+        if self.semantics.pop_last_root {
+            self.asm.alu32(Alu::Xor, R15, R15);
+            self.asm.test64(Rax, Rax);
+            self.asm.jcc(Cond::E, popped);
+        }
+        self.asm.load64(Rdx, ctx(offset_of!(Ctx, roots)));
+        self.asm.load32(R15, Mem::index(Rdx, Rax, 4, -4));
+        self.asm.bind(popped);
+        self.set_success(true);
+        self.asm.jmp(next);
+        self.asm.bind(fail);
+        self.set_success(false);
+    }
+
+    /// Calls into the runtime with the context as the first argument.
+    fn call(&mut self, f: *const ()) {
+        self.asm.mov64(Rdi, R13);
+        self.asm.mov_imm(Rax, f as u64);
+        self.asm.call_reg(Rax);
+    }
+
+    /// Jumps to the op with the index in `rax`.
+    fn jump_dynamic(&mut self) {
+        self.asm.load64(Rdx, ctx(offset_of!(Ctx, labels)));
+        self.asm.jmp_mem(Mem::index(Rdx, Rax, 8, 0));
+    }
+}
+
+/// Encodes an id as it is stored in a [`Node`].
+fn encode(id: Option<NodeId>) -> u32 {
+    id.map_or(0, |id| id.0.get())
+}
+
+fn decode(id: u32) -> Option<NodeId> {
+    NonZeroU32::new(id).map(NodeId)
+}
+
+/// A field of the context.
+fn ctx(offset: usize) -> Mem {
+    Mem::base(R13, offset as i32)
+}
+
+impl Ctx {
+    /// Lends the state of the VM to the code.
+    unsafe fn new(vm: *mut VM, labels: &[usize]) -> Self {
+        let mut ctx = Ctx {
+            nodes: std::ptr::null_mut(),
+            len: 0,
+            limit: 0,
+            free: 0,
+            cursor: encode(Some((*vm).tree.cursor)),
+            roots: std::ptr::null_mut(),
+            roots_len: 0,
+            roots_cap: 0,
+            top: 0,
+            success: (*vm).success as u32,
+            pc: (*vm).pc,
+            labels: labels.as_ptr(),
+            vm,
+        };
+        ctx.load_nodes();
+        ctx.load_roots();
+        ctx
+    }
+
+    /// Gives the state back to the VM.
+    unsafe fn store(&mut self) {
+        self.store_nodes();
+        self.store_roots();
+        let vm = &mut *self.vm;
+        vm.tree.cursor = decode(self.cursor).expect("cursor is not a node");
+        vm.success = self.success != 0;
+        vm.pc = self.pc;
+    }
+
+    unsafe fn load_nodes(&mut self) {
+        let tree = &mut (*self.vm).tree.tree;
+        self.nodes = tree.nodes.as_mut_ptr().wrapping_sub(1).cast();
+        self.len = tree.nodes.len();
+        self.limit = tree.nodes.capacity().min(tree.limit());
+        self.free = encode(tree.free);
+    }
+
+    unsafe fn store_nodes(&mut self) {
+        let tree = &mut (*self.vm).tree.tree;
+        // The code has initialized the nodes it appended.
+        tree.nodes.set_len(self.len);
+        tree.free = decode(self.free);
+    }
+
+    unsafe fn load_roots(&mut self) {
+        let root_stack = &mut (*self.vm).tree.root_stack;
+        self.roots = root_stack.as_mut_ptr();
+        self.roots_len = root_stack.len();
+        self.roots_cap = root_stack.capacity();
+        self.top = encode(root_stack.last().copied());
+    }
+
+    unsafe fn store_roots(&mut self) {
+        // The code has initialized the roots it pushed.
+        (*self.vm).tree.root_stack.set_len(self.roots_len);
+    }
+}
+
+/// Allocates a node in the arena, for when it would grow or a freed node has
+/// children to free. Returns 0 when out of memory.
+unsafe extern "sysv64" fn alloc_node(ctx: *mut Ctx) -> u32 {
+    let ctx = &mut *ctx;
+    ctx.store_nodes();
+    let id = (*ctx.vm).tree.tree.new_node();
+    ctx.load_nodes();
+    encode(id.ok())
+}
+
+/// Returns whether a chain of `n` children fits in the arena, as in
+/// `MultiTree::has_capacity`, replacing the child of `cursor` on the left, or
+/// on the right when `right` is 1.
+unsafe extern "sysv64" fn has_capacity(ctx: *mut Ctx, cursor: u32, n: usize, right: u32) -> u32 {
+    let ctx = &mut *ctx;
+    let Some(cursor) = decode(cursor) else {
+        return 0;
+    };
+    ctx.store_nodes();
+    let tree = &(*ctx.vm).tree.tree;
+    let node = &tree[cursor];
+    let replaced = if right != 0 { node.right } else { node.left };
+    tree.has_capacity(n, replaced) as u32
+}
+
+/// Pushes a root, for when the root stack would grow. Returns 0 when the
+/// stack cannot grow.
+unsafe extern "sysv64" fn push_root(ctx: *mut Ctx, id: u32) -> u32 {
+    let ctx = &mut *ctx;
+    let Some(id) = decode(id) else {
+        return 0;
+    };
+    ctx.store_roots();
+    let root_stack = &mut (*ctx.vm).tree.root_stack;
+    let pushed = root_stack.try_reserve(1).is_ok();
+    if pushed {
+        root_stack.push(id);
+    }
+    ctx.load_roots();
+    pushed as u32
+}
+
+/// Executes `(` with [`Semantics::break_keeps_loop`]. Returns 0 when the loop
+/// stack cannot grow.
+unsafe extern "sysv64" fn loop_head(ctx: *mut Ctx, head: usize, tail: usize) -> u32 {
+    let loop_stack = &mut (*(*ctx).vm).loop_stack;
+    if loop_stack.try_reserve(1).is_err() {
+        return 0;
+    }
+    loop_stack.push((head, tail));
+    1
+}
+
+/// Executes `)` with [`Semantics::break_keeps_loop`], except for setting the
+/// success flag. Returns the op to jump to, 0 to continue, or
+/// `usize::MAX` when the loop stack is empty.
+unsafe extern "sysv64" fn loop_tail(ctx: *mut Ctx, success: u32) -> usize {
+    let loop_stack = &mut (*(*ctx).vm).loop_stack;
+    let Some(&(head, _)) = loop_stack.last() else {
+        return usize::MAX;
+    };
+    if success != 0 {
+        head + 1
+    } else {
+        loop_stack.pop();
+        0
+    }
+}
+
+/// Returns the op that a successful `?` jumps to with
+/// [`Semantics::break_keeps_loop`].
+unsafe extern "sysv64" fn break_target(ctx: *mut Ctx) -> usize {
+    let vm = &*(*ctx).vm;
+    let tail = vm
+        .loop_stack
+        .last()
+        .map_or(vm.code.len(), |&(_, tail)| tail);
+    if vm.semantics.break_restarts && tail == vm.code.len() {
+        0
+    } else {
+        tail + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tree::OutOfMemory;
+    use crate::{corpus, difftest_engine, Engine, Program, RunStatus, Semantics, VMError, VM};
+
+    const SEMANTICS: [Semantics; 2] = [Semantics::LEAFY, Semantics::REFERENCE];
+
+    /// Runs a VM with the interpreter and a copy of it with the JIT, checks
+    /// that they end in the same state, and returns the result.
+    fn check(vm: &VM, max_steps: u64, max_nodes: Option<usize>) -> Result<RunStatus, VMError> {
+        let mut interpreter = vm.clone();
+        interpreter.set_max_nodes(max_nodes);
+        let mut jit = interpreter.clone();
+        jit.set_engine(Engine::Jit).unwrap();
+        assert_eq!(jit.engine(), Engine::Jit);
+        let res = interpreter.run_for(max_steps);
+        assert_eq!(jit.run_for(max_steps), res, "{}", vm.prog);
+        assert_eq!(jit.pc, interpreter.pc, "{}", vm.prog);
+        assert_eq!(jit.success, interpreter.success, "{}", vm.prog);
+        assert_eq!(jit.loop_stack, interpreter.loop_stack, "{}", vm.prog);
+        assert_eq!(jit.tree.diff(&interpreter.tree), None, "{}", vm.prog);
+        res
+    }
+
+    #[test]
+    fn jit_corpus() {
+        for (name, prog) in corpus::programs() {
+            for semantics in SEMANTICS {
+                for max_steps in [1_000, 1_000_000] {
+                    let res = difftest_engine(&prog, semantics, Engine::Jit, max_steps);
+                    assert_eq!(res, Ok(()), "{name} {semantics:?} {max_steps}");
+                }
+                let vms = [
+                    VM::with_semantics(prog.clone(), semantics),
+                    VM::optimized(prog.clone(), semantics),
+                ];
+                for vm in &vms {
+                    // Small enough that some programs run out of nodes.
+                    for max_nodes in [None, Some(50), Some(10_000)] {
+                        _ = check(vm, 1_000_000, max_nodes);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn jit_loop_stack_underflow() {
+        // Only reachable by starting at the `)`, as loops are balanced.
+        let mut vm = VM::with_semantics(Program::parse("()").unwrap(), Semantics::REFERENCE);
+        vm.pc = 1;
+        assert_eq!(check(&vm, 10, None), Err(VMError::LoopStackUnderflow));
+        vm.semantics = Semantics::LEAFY;
+        assert_eq!(check(&vm, 10, None), Ok(RunStatus::Finished));
+    }
+
+    #[test]
+    fn jit_deleted_tree_root() {
+        for src in ["-", "+<^-", "+<{-}-"] {
+            let prog = Program::parse(src).unwrap();
+            let vm = VM::with_semantics(prog.clone(), Semantics::REFERENCE);
+            assert_eq!(check(&vm, 10, None), Err(VMError::DeletedTreeRoot), "{src}");
+            let vm = VM::with_semantics(prog, Semantics::LEAFY);
+            assert_eq!(check(&vm, 10, None), Ok(RunStatus::Finished), "{src}");
+        }
+    }
+
+    #[test]
+    fn jit_out_of_memory() {
+        let prog = Program::parse("+<+<+<+<+<*>*>*>*>").unwrap();
+        for semantics in SEMANTICS {
+            let vms = [
+                VM::with_semantics(prog.clone(), semantics),
+                VM::optimized(prog.clone(), semantics),
+            ];
+            for vm in &vms {
+                for max_nodes in [1, 3, 8] {
+                    let res = check(vm, 100, Some(max_nodes));
+                    assert_eq!(res, Err(VMError::OutOfMemory(OutOfMemory)));
+                }
+                assert_eq!(check(vm, 100, Some(10)), Ok(RunStatus::Finished));
+            }
+        }
+        // Deleted nodes are reused.
+        let vm = VM::new(Program::parse("(+<-)").unwrap());
+        assert_eq!(check(&vm, 1_000, Some(2)), Ok(RunStatus::OutOfFuel));
+        // A chain that only fits by reusing deleted nodes, followed by one that
+        // does not fit, which fails without changing the tree.
+        let prog = Program::parse("+<+<+<^^-+<+<+<*>*>").unwrap();
+        for semantics in SEMANTICS {
+            let vm = VM::optimized(prog.clone(), semantics);
+            assert_eq!(
+                check(&vm, 100, Some(4)),
+                Err(VMError::OutOfMemory(OutOfMemory))
+            );
+            assert_eq!(check(&vm, 100, Some(6)), Ok(RunStatus::Finished));
+        }
+    }
+
+    #[test]
+    fn jit_clone() {
+        let mut vm = VM::new(Program::parse("+<+<(^)*>").unwrap());
+        vm.set_engine(Engine::Jit).unwrap();
+        let mut clone = vm.clone();
+        drop(vm);
+        assert_eq!(clone.engine(), Engine::Jit);
+        assert_eq!(clone.run(), Ok(()));
+        assert_eq!(clone.pc(), 9);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{io, ptr};
+
+/// A block of machine code, mapped read-only and executable.
+pub(super) struct ExecMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The code is immutable once mapped.
+unsafe impl Send for ExecMemory {}
+unsafe impl Sync for ExecMemory {}
+
+impl ExecMemory {
+    /// Maps a copy of the code. Fails when the operating system refuses the
+    /// mapping.
+    pub(super) fn new(code: &[u8]) -> io::Result<Self> {
+        let len = code.len().max(1);
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            // Unmapped on drop, including when protecting it fails.
+            let mem = ExecMemory {
+                ptr: ptr.cast(),
+                len,
+            };
+            mem.ptr.copy_from_nonoverlapping(code.as_ptr(), code.len());
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(mem)
+        }
+    }
+
+    pub(super) fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+}
+
+impl Drop for ExecMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
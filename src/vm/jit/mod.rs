@@ -0,0 +1,5 @@
+mod asm;
+mod compile;
+mod exec;
+
+pub(crate) use compile::*;
@@ -0,0 +1,6 @@
+mod interp;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod jit;
+mod threaded;
+
+pub use interp::*;
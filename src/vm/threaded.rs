@@ -0,0 +1,370 @@
+use crate::{Code, Op, RunStatus, Semantics, VMError, VM};
+
+/// Compiled code as a table of handlers, one per op, with their operands
+/// decoded and the semantics resolved ahead of time.
+#[derive(Clone, Debug)]
+pub(crate) struct Threaded {
+    ops: Vec<ThreadedOp>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ThreadedOp {
+    handler: Handler,
+    arg: usize,
+}
+
+/// Executes the op at a program counter with its operand and returns
+/// the next program counter. The program counter is kept out of the VM while
+/// running and stored back afterwards, so that it stays in a register.
+type Handler = fn(&mut VM, usize, usize) -> Result<usize, VMError>;
+
+impl Threaded {
+    pub(crate) fn compile(code: &Code, semantics: Semantics) -> Self {
+        let Semantics {
+            pop_last_root,
+            delete_root,
+            break_keeps_loop,
+            break_restarts,
+        } = semantics;
+        let delete: Handler = if delete_root {
+            delete::<true>
+        } else {
+            delete::<false>
+        };
+        let ops = code.ops().iter().map(|&op| {
+            let (handler, arg): (Handler, usize) = match op {
+                Op::MoveLeft => (move_left, 0),
+                Op::MoveRight => (move_right, 0),
+                Op::MoveUp => (move_up, 0),
+                Op::PushRoot => (push_root, 0),
+                Op::PopRoot if pop_last_root => (pop_last_root_, 0),
+                Op::PopRoot => (pop_root, 0),
+                Op::LoopHead(tail) if break_keeps_loop => (loop_head_stack, tail),
+                Op::LoopHead(_) => (nop, 0),
+                Op::LoopTail(_) if break_keeps_loop => (loop_tail_stack, 0),
+                Op::LoopTail(head) => (loop_tail, head),
+                Op::NewLeft => (new_left, 0),
+                Op::NewRight => (new_right, 0),
+                Op::Delete => (delete, 0),
+                Op::Break(_) if break_keeps_loop && break_restarts => (break_stack::<true>, 0),
+                Op::Break(_) if break_keeps_loop => (break_stack::<false>, 0),
+                Op::Break(tail) if break_restarts && tail == code.len() => (break_restart, 0),
+                Op::Break(tail) => (break_, tail),
+                Op::MoveUpToRoot => (move_up_to_root, 0),
+                Op::MoveLeftToEnd => (move_left_to_end, 0),
+                Op::MoveRightToEnd => (move_right_to_end, 0),
+                Op::RepeatMoveUp(n) => (repeat_move_up, n),
+                Op::NewLeftChain(n) => (new_left_chain, n),
+                Op::NewRightChain(n) => (new_right_chain, n),
+                Op::DeleteLeft if delete_root => (delete_left::<true>, 0),
+                Op::DeleteLeft => (delete_left::<false>, 0),
+                Op::DeleteRight if delete_root => (delete_right::<true>, 0),
+                Op::DeleteRight => (delete_right::<false>, 0),
+            };
+            ThreadedOp { handler, arg }
+        });
+        Threaded { ops: ops.collect() }
+    }
+
+    pub(crate) fn run(&self, vm: &mut VM) -> Result<(), VMError> {
+        if vm.pc >= self.ops.len() {
+            return Err(VMError::Terminated);
+        }
+        let mut pc = vm.pc;
+        let res = loop {
+            let Some(op) = self.ops.get(pc) else {
+                break Ok(());
+            };
+            match (op.handler)(vm, op.arg, pc) {
+                Ok(next) => pc = next,
+                Err(err) => break Err(err),
+            }
+        };
+        vm.pc = pc;
+        res
+    }
+
+    pub(crate) fn run_for(&self, vm: &mut VM, steps: u64) -> Result<RunStatus, VMError> {
+        if vm.pc >= self.ops.len() {
+            return Err(VMError::Terminated);
+        }
+        let mut pc = vm.pc;
+        let mut res = Ok(());
+        for _ in 0..steps {
+            let Some(op) = self.ops.get(pc) else {
+                break;
+            };
+            match (op.handler)(vm, op.arg, pc) {
+                Ok(next) => pc = next,
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            }
+        }
+        vm.pc = pc;
+        res?;
+        if pc >= self.ops.len() {
+            Ok(RunStatus::Finished)
+        } else {
+            Ok(RunStatus::OutOfFuel)
+        }
+    }
+
+    pub(crate) fn step(&self, vm: &mut VM) -> Result<(), VMError> {
+        let op = self.ops.get(vm.pc).ok_or(VMError::Terminated)?;
+        vm.pc = (op.handler)(vm, op.arg, vm.pc)?;
+        Ok(())
+    }
+}
+
+fn nop(_: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    Ok(pc + 1)
+}
+
+fn move_left(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.success = vm.tree.move_left();
+    Ok(pc + 1)
+}
+
+fn move_right(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.success = vm.tree.move_right();
+    Ok(pc + 1)
+}
+
+fn move_up(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.success = vm.tree.move_up();
+    Ok(pc + 1)
+}
+
+fn push_root(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.tree.push_root();
+    Ok(pc + 1)
+}
+
+fn pop_root(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.success = vm.tree.pop_root().is_some();
+    Ok(pc + 1)
+}
+
+fn pop_last_root_(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.success = vm.tree.pop_last_root().is_some();
+    Ok(pc + 1)
+}
+
+fn loop_head_stack(vm: &mut VM, tail: usize, pc: usize) -> Result<usize, VMError> {
+    vm.loop_stack.push((pc, tail));
+    Ok(pc + 1)
+}
+
+fn loop_tail(vm: &mut VM, head: usize, pc: usize) -> Result<usize, VMError> {
+    let next = if vm.success { head + 1 } else { pc + 1 };
+    vm.success = true;
+    Ok(next)
+}
+
+fn loop_tail_stack(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    let Some(&(head, _)) = vm.loop_stack.last() else {
+        return Err(VMError::LoopStackUnderflow);
+    };
+    let next = if vm.success {
+        head + 1
+    } else {
+        vm.loop_stack.pop();
+        pc + 1
+    };
+    vm.success = true;
+    Ok(next)
+}
+
+fn new_left(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.tree.new_left()?;
+    vm.success = true;
+    Ok(pc + 1)
+}
+
+fn new_right(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.tree.new_right()?;
+    vm.success = true;
+    Ok(pc + 1)
+}
+
+fn delete<const DELETE_ROOT: bool>(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    delete_at_cursor::<DELETE_ROOT>(vm)?;
+    Ok(pc + 1)
+}
+
+#[inline(always)]
+fn delete_at_cursor<const DELETE_ROOT: bool>(vm: &mut VM) -> Result<(), VMError> {
+    if DELETE_ROOT && vm.tree.at_root() {
+        if !vm.tree.detach() {
+            return Err(VMError::DeletedTreeRoot);
+        }
+        vm.success = true;
+    } else {
+        vm.success = vm.tree.delete();
+    }
+    Ok(())
+}
+
+fn break_(vm: &mut VM, tail: usize, pc: usize) -> Result<usize, VMError> {
+    vm.success = vm.tree.at_root();
+    Ok(if vm.success { tail + 1 } else { pc + 1 })
+}
+
+/// `?` outside of a loop, with [`Semantics::break_restarts`].
+fn break_restart(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.success = vm.tree.at_root();
+    Ok(if vm.success { 0 } else { pc + 1 })
+}
+
+fn break_stack<const RESTARTS: bool>(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    vm.success = vm.tree.at_root();
+    if !vm.success {
+        return Ok(pc + 1);
+    }
+    Ok(match vm.loop_stack.last() {
+        Some(&(_, tail)) => tail + 1,
+        None if RESTARTS => 0,
+        None => vm.code.len() + 1,
+    })
+}
+
+fn move_up_to_root(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    while vm.tree.move_up() {}
+    vm.success = true;
+    Ok(pc + 1)
+}
+
+fn move_left_to_end(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    while vm.tree.move_left() {}
+    vm.success = true;
+    Ok(pc + 1)
+}
+
+fn move_right_to_end(vm: &mut VM, _: usize, pc: usize) -> Result<usize, VMError> {
+    while vm.tree.move_right() {}
+    vm.success = true;
+    Ok(pc + 1)
+}
+
+fn repeat_move_up(vm: &mut VM, n: usize, pc: usize) -> Result<usize, VMError> {
+    for _ in 0..n {
+        vm.success = vm.tree.move_up();
+        if !vm.success {
+            break;
+        }
+    }
+    Ok(pc + 1)
+}
+
+fn new_left_chain(vm: &mut VM, n: usize, pc: usize) -> Result<usize, VMError> {
+    vm.tree.new_left_chain(n)?;
+    vm.success = true;
+    Ok(pc + 1)
+}
+
+fn new_right_chain(vm: &mut VM, n: usize, pc: usize) -> Result<usize, VMError> {
+    vm.tree.new_right_chain(n)?;
+    vm.success = true;
+    Ok(pc + 1)
+}
+
+fn delete_left<const DELETE_ROOT: bool>(
+    vm: &mut VM,
+    _: usize,
+    pc: usize,
+) -> Result<usize, VMError> {
+    vm.tree.new_left_chain(1)?;
+    delete_at_cursor::<DELETE_ROOT>(vm)?;
+    Ok(pc + 1)
+}
+
+fn delete_right<const DELETE_ROOT: bool>(
+    vm: &mut VM,
+    _: usize,
+    pc: usize,
+) -> Result<usize, VMError> {
+    vm.tree.new_right_chain(1)?;
+    delete_at_cursor::<DELETE_ROOT>(vm)?;
+    Ok(pc + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tree::OutOfMemory;
+    use crate::{corpus, Engine, Program, RunStatus, Semantics, VMError, VM};
+
+    const SEMANTICS: [Semantics; 2] = [Semantics::LEAFY, Semantics::REFERENCE];
+
+    /// Runs a program with the interpreter and the threaded engine, as is and
+    /// optimized, and checks that the results and final states are the same.
+    fn check_same(name: &str, prog: &Program, max_steps: u64) {
+        for semantics in SEMANTICS {
+            for optimize in [false, true] {
+                let new = |engine| {
+                    let mut vm = if optimize {
+                        VM::optimized(prog.clone(), semantics)
+                    } else {
+                        VM::with_semantics(prog.clone(), semantics)
+                    };
+                    vm.set_engine(engine).unwrap();
+                    let res = vm.run_for(max_steps);
+                    (vm, res)
+                };
+                let (a, res_a) = new(Engine::Interpreter);
+                let (b, res_b) = new(Engine::Threaded);
+                let msg = format!("{name} {semantics:?} optimize={optimize} {max_steps}");
+                assert_eq!(res_a, res_b, "{msg}");
+                assert_eq!(a.pc, b.pc, "{msg}");
+                assert_eq!(a.success, b.success, "{msg}");
+                assert_eq!(a.tree().diff(b.tree()), None, "{msg}");
+            }
+        }
+    }
+
+    #[test]
+    fn threaded_corpus() {
+        for (name, prog) in corpus::programs() {
+            // Stop some runs midway, to compare suspended states too.
+            for max_steps in [1_000, 1_000_000] {
+                check_same(&name, &prog, max_steps);
+            }
+        }
+    }
+
+    #[test]
+    fn threaded_errors() {
+        let programs = [
+            // DeletedTreeRoot, or deleting the root, depending on semantics
+            "-",
+            "+<{^-",
+            // Popping the last root
+            "}}",
+            // Breaking and looping with the loop stack
+            "+<(?)(^?+)*>(-?)",
+            "((?))?",
+            // Infinite loops
+            "(+<-)",
+            "+<(^+<)",
+        ];
+        for src in programs {
+            check_same(src, &Program::parse(src).unwrap(), 1_000);
+        }
+    }
+
+    #[test]
+    fn threaded_out_of_memory() {
+        let prog = Program::parse("+<+<+<^^-+<+<+<*>*>").unwrap();
+        for semantics in SEMANTICS {
+            let mut vm = VM::optimized(prog.clone(), semantics);
+            vm.set_engine(Engine::Threaded).unwrap();
+            vm.set_max_nodes(Some(4));
+            assert_eq!(vm.run_for(100), Err(VMError::OutOfMemory(OutOfMemory)));
+            assert_eq!(vm.pc(), 4);
+            assert_eq!(vm.tree().unrooted().allocated(), 4);
+            vm.set_max_nodes(Some(6));
+            assert_eq!(vm.run_for(100), Ok(RunStatus::Finished));
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Tests of the `leafy` command line.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// Runs `leafy` with the given arguments and stdin.
+fn leafy(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_leafy"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Commands that fail on their arguments exit without reading stdin.
+    let res = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    if let Err(err) = res {
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+    child.wait_with_output().unwrap()
+}
+
+/// Returns the path of every `.leaf` file under `dir`, sorted.
+fn leaf_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(leaf_files(&path));
+        } else if path.extension().is_some_and(|ext| ext == "leaf") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+#[test]
+fn fmt_check_formatted() {
+    let out = leafy(&["fmt", "--check", "programs/tutorial/4_loops.leaf"], "");
+    assert!(out.status.success());
+    assert_eq!(out.stderr, b"");
+    let out = leafy(&["fmt", "--check"], "+(\n  <\n)       # comment\n");
+    assert!(out.status.success());
+}
+
+#[test]
+fn fmt_check_unformatted() {
+    let out = leafy(&["fmt", "--check"], "+(\n<\n)   # comment \n");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "<stdin>: not formatted\n"
+    );
+    assert_eq!(out.stdout, b"");
+}
+
+#[test]
+fn fmt_stdin() {
+    let out = leafy(&["fmt"], "+(\n<\n)   # comment \n");
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "+(\n  <\n)       # comment\n"
+    );
+}
+
+#[test]
+fn run_max_nodes() {
+    for semantics in ["leafy", "reference"] {
+        let out = leafy(
+            &["run", "--max-nodes", "10", "--semantics", semantics],
+            "+(<+)",
+        );
+        assert_eq!(out.status.code(), Some(1));
+        assert_eq!(String::from_utf8_lossy(&out.stderr), "out of memory\n");
+    }
+    let out = leafy(&["run", "--max-nodes", "2", "--max-steps", "1000"], "(+<-)");
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "exceeded step limit of 1000\n"
+    );
+}
+
+#[test]
+fn run_meta() {
+    let native = leafy(&["run"], "+<*>^");
+    let meta = leafy(&["run", "--meta", "1"], "+<*>^");
+    assert!(meta.status.success());
+    assert_eq!(meta.stdout, native.stdout);
+}
+
+#[test]
+fn run_meta_unsupported() {
+    let out = leafy(&["run", "--meta", "1"], "+(<?)");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "program uses `?`, which leaf.leaf does not implement\n"
+    );
+    let out = leafy(&["run", "--meta", "2"], "+<");
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "--meta is at most 1, since leaf.leaf uses `?`, which it does not implement\n"
+    );
+}
+
+#[test]
+fn difftest_skips_break() {
+    let out = leafy(&["difftest", "programs/tutorial"], "");
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("6_conditional.leaf: skipped: program uses `?`"));
+    assert!(stdout.ends_with("7 passed, 0 failed, 3 skipped\n"));
+}
+
+#[test]
+fn difftest_engine() {
+    for semantics in ["leafy", "reference"] {
+        let args = [
+            "difftest",
+            "--engine",
+            "threaded",
+            "--semantics",
+            semantics,
+            "--max-steps",
+            "100000",
+            "programs/tutorial",
+        ];
+        let out = leafy(&args, "");
+        assert!(out.status.success(), "{semantics}");
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.ends_with("10 passed, 0 failed\n"), "{semantics}");
+    }
+    let out = leafy(
+        &["difftest", "--semantics", "leafy", "programs/tutorial"],
+        "",
+    );
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn bf2leaf() {
+    let out = leafy(&["bf2leaf"], ">+[>++>+++[-<]>>]");
+    assert!(out.status.success());
+    let leaf = String::from_utf8(out.stdout).unwrap();
+    let out = leafy(&["check"], &leaf);
+    assert!(out.status.success());
+    let out = leafy(&["bf2leaf"], "+]");
+    assert_eq!(out.status.code(), Some(1));
+}
+
+#[test]
+fn bf2leaf_run_tape() {
+    let out = leafy(&["bf2leaf"], ">+[>++>+++[-<]>>]");
+    assert!(out.status.success());
+    let leaf = String::from_utf8(out.stdout).unwrap();
+    let out = leafy(&["run", "--output", "tape"], &leaf);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 29 [0] 181 214\n",
+    );
+}
+
+#[test]
+fn leaf2bf_run() {
+    let prog = "programs/tutorial/4_loops.leaf";
+    let native = leafy(&["run", prog], "");
+    let out = leafy(&["leaf2bf", "--run", "--cell-bits", "16", prog], "");
+    assert!(out.status.success());
+    // Node ids are numbered differently, but the nodes are listed in the same
+    // order.
+    let shape =
+        |dot: &[u8]| String::from_utf8_lossy(dot).replace(|ch: char| ch.is_ascii_digit(), "");
+    assert_eq!(shape(&out.stdout), shape(&native.stdout));
+}
+
+#[test]
+fn leaf2bf_cell_bits() {
+    let out = leafy(&["leaf2bf", "--run", "--cell-bits", "65"], "+");
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "cells of 65 bits are unsupported (must be between 1 and 64)\n",
+    );
+}
+
+#[test]
+fn run_optimize() {
+    for src in ["+<+<+<(^)*>*>-", "+(<+)"] {
+        let plain = leafy(&["run", "--max-nodes", "6"], src);
+        let optimized = leafy(&["run", "--max-nodes", "6", "--optimize"], src);
+        assert_eq!(optimized.status.code(), plain.status.code(), "{src}");
+        assert_eq!(optimized.stdout, plain.stdout, "{src}");
+        assert_eq!(optimized.stderr, plain.stderr, "{src}");
+    }
+}
+
+#[test]
+fn run_engine() {
+    let src = "+<+<+<(^)*>*>-";
+    let interpreter = leafy(&["run", "--engine", "interpreter"], src);
+    for args in [
+        &["--engine", "threaded"][..],
+        &["--engine", "threaded", "--optimize"],
+    ] {
+        let out = leafy(&[&["run"], args].concat(), src);
+        assert!(out.status.success(), "{args:?}");
+        assert_eq!(out.stdout, interpreter.stdout, "{args:?}");
+    }
+    let out = leafy(&["run", "--engine", "fast"], src);
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn compile_c() {
+    if Command::new("cc").arg("--version").output().is_err() {
+        eprintln!("skipping: no C compiler");
+        return;
+    }
+    let tmp = Path::new(env!("CARGO_TARGET_TMPDIR")).join("compile_c");
+    fs::create_dir_all(&tmp).unwrap();
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("programs");
+    let mut sources: Vec<(String, String)> = leaf_files(&programs)
+        .into_iter()
+        .map(|path| {
+            (
+                path.strip_prefix(&programs).unwrap().display().to_string(),
+                fs::read_to_string(&path).unwrap(),
+            )
+        })
+        .collect();
+    // Errors, which depend on the semantics.
+    for src in ["-", "+<{^-", "}}<", "+<(?)(^?+)*>(-?)"] {
+        sources.push((src.to_owned(), src.to_owned()));
+    }
+    for (name, src) in &sources {
+        for semantics in ["leafy", "reference"] {
+            let args = ["run", "--semantics", semantics, "--max-steps", "10000000"];
+            let native = leafy(&args, src);
+            // Some examples loop forever under the reference semantics.
+            if String::from_utf8_lossy(&native.stderr).starts_with("exceeded step limit") {
+                continue;
+            }
+            for optimize in [false, true] {
+                let mut args = vec!["compile", "--emit", "c", "--semantics", semantics];
+                if optimize {
+                    args.push("--optimize");
+                }
+                let out = leafy(&args, src);
+                assert!(out.status.success(), "{name}");
+                let c = tmp.join("prog.c");
+                let exe = tmp.join("prog");
+                fs::write(&c, out.stdout).unwrap();
+                let cc = Command::new("cc")
+                    .args(["-std=c99", "-o"])
+                    .args([&exe, &c])
+                    .output()
+                    .unwrap();
+                assert!(
+                    cc.status.success(),
+                    "{name}: {}",
+                    String::from_utf8_lossy(&cc.stderr)
+                );
+                let out = Command::new(&exe).output().unwrap();
+                let what = format!("{name} {semantics} optimize={optimize}");
+                assert_eq!(out.status.code(), native.status.code(), "{what}");
+                assert_eq!(out.stdout, native.stdout, "{what}");
+                assert_eq!(out.stderr, native.stderr, "{what}");
+            }
+        }
+    }
+}
+
+#[test]
+fn compile_rust() {
+    let out = leafy(&["compile", "--emit", "rust"], "+(<?)");
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout)
+        .starts_with("fn run(tree: &mut ::leafy::tree::RootedTree)"));
+    let out = leafy(&["compile", "--emit", "rust", "--optimize"], "+(<?)");
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "--emit rust only supports --semantics leafy, without --optimize\n",
+    );
+}